@@ -0,0 +1,128 @@
+//! Local record of the objects this client has uploaded.
+//!
+//! The root kept here is computed by the client itself before the data
+//! leaves the machine, so it is the only thing later proofs are checked
+//! against; nothing the server says about roots is trusted.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use merkle_storage_core::{Chunking, Error, ObjectId, Result, TreeHasher};
+
+use crate::session::TrustedVersion;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CatalogueEntry {
+    pub name: String,
+    pub object_id: ObjectId,
+    #[serde(with = "hex::serde")]
+    pub root: [u8; 32],
+    /// Length of the uploaded file in bytes; 0 for entries recorded before
+    /// sizes were kept.
+    #[serde(default)]
+    pub size: usize,
+    pub leaf_count: usize,
+    pub chunking: Chunking,
+    #[serde(flatten)]
+    pub hasher: TreeHasher,
+    /// Seconds since the Unix epoch.
+    pub uploaded_at: u64,
+    pub server: String,
+    /// Versions the object had before it was updated, oldest first.
+    #[serde(default)]
+    pub history: Vec<PastVersion>,
+}
+
+/// Root, leaf count and size of an object before an update, as confirmed by
+/// this client at the time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PastVersion {
+    #[serde(with = "hex::serde")]
+    pub root: [u8; 32],
+    pub leaf_count: usize,
+    pub size: usize,
+}
+
+impl CatalogueEntry {
+    /// The version recorded here, as proofs from the server are checked against.
+    pub fn trusted(&self) -> TrustedVersion {
+        TrustedVersion { object_id: self.object_id, root: self.root, leaf_count: self.leaf_count, hasher: self.hasher }
+    }
+
+    pub fn current_version(&self) -> PastVersion {
+        PastVersion { root: self.root, leaf_count: self.leaf_count, size: self.size }
+    }
+
+    /// The entry as it was at the version with root `root`, given in hex,
+    /// if this client ever recorded that version.
+    pub fn at_version(&self, root: &str) -> Result<CatalogueEntry> {
+        let version = hex::decode(root)
+            .ok()
+            .and_then(|root| {
+                self.history
+                    .iter()
+                    .chain([&self.current_version()])
+                    .rev()
+                    .find(|version| version.root[..] == root[..])
+                    .cloned()
+            })
+            .ok_or_else(|| Error::NotFound(format!("{} never had a recorded version with root {}", self.name, root)))?;
+        Ok(CatalogueEntry {
+            root: version.root,
+            leaf_count: version.leaf_count,
+            size: version.size,
+            ..self.clone()
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Catalogue {
+    path: PathBuf,
+    entries: Vec<CatalogueEntry>,
+}
+
+impl Catalogue {
+    /// Loads the catalogue, starting an empty one if the file does not exist.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Catalogue> {
+        let path = path.as_ref().to_path_buf();
+        let entries = match fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Catalogue { path, entries })
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let json = serde_json::to_vec_pretty(&self.entries)?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, json)?;
+        fs::rename(&tmp, &self.path)
+    }
+
+    pub fn entries(&self) -> &[CatalogueEntry] {
+        &self.entries
+    }
+
+    pub fn add(&mut self, entry: CatalogueEntry) {
+        self.entries.retain(|e| e.object_id != entry.object_id);
+        self.entries.push(entry);
+    }
+
+    /// Finds an object by id, or by name, preferring the latest upload.
+    pub fn find(&self, object: &str) -> Result<&CatalogueEntry> {
+        let by_id = object.parse::<ObjectId>().ok();
+        self.entries
+            .iter()
+            .rev()
+            .find(|e| Some(e.object_id) == by_id)
+            .or_else(|| self.entries.iter().rev().find(|e| e.name == object))
+            .ok_or_else(|| Error::NotFound(format!("{} is not in the catalogue at {}", object, self.path.display())))
+    }
+}
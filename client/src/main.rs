@@ -1,213 +1,688 @@
-use std::{net::TcpStream, io::{Write, Read}};
-
-use rand::seq::SliceRandom;
-use rand::Rng;
-use serde::{Serialize, Deserialize};
-use rs_merkle::{Hasher, MerkleTree, MerkleProof};
-use sha2::{Sha256, Digest, digest::FixedOutput};
-
-#[derive(Debug, Serialize, Deserialize, Clone, Eq, Hash, PartialEq)]
-pub struct Sha256Algorithm {}
-
-impl Hasher for Sha256Algorithm {
-    type Hash = [u8; 32];
-
-    fn hash(data: &[u8]) -> [u8; 32] {
-        let mut hasher = Sha256::new();
-
-        hasher.update(data);
-        <[u8; 32]>::from(hasher.finalize_fixed())
-    }
+mod catalogue;
+mod session;
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use merkle_storage_core::sampling;
+use merkle_storage_core::{
+    compute_leaves, compute_merkle_tree, covering_chunks, hash_leaves, Chunking, Error, HashAlgorithm, Limits,
+    ObjectId, Result, TreeHasher, DEFAULT_CHUNK_SIZE,
+};
+use serde::Serialize;
+
+use crate::catalogue::{Catalogue, CatalogueEntry, PastVersion};
+use crate::session::TrustedVersion;
+
+const DEFAULT_SERVER: &str = "127.0.0.1:8080";
+
+/// Stores files on a peer and audits them with Merkle proofs.
+#[derive(Debug, Parser)]
+struct Cli {
+    /// Storage server to talk to [default: the object's server, or 127.0.0.1:8080]
+    #[arg(long, global = true, env = "MERKLE_STORAGE_SERVER")]
+    server: Option<String>,
+
+    /// File recording the objects uploaded from this machine
+    #[arg(long, global = true, default_value = ".merkle-storage/catalogue.json")]
+    catalogue: PathBuf,
+
+    /// How results are printed
+    #[arg(long, global = true, value_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    #[command(flatten)]
+    limits: LimitArgs,
+
+    #[command(subcommand)]
+    command: Command,
 }
 
+/// Bounds on what the client sends to and accepts from the server.
+#[derive(Debug, Args)]
+struct LimitArgs {
+    /// Largest frame an upload may take, in bytes
+    #[arg(long, global = true, default_value_t = Limits::default().max_store_frame)]
+    max_store_frame: usize,
 
-fn main() {
-    let message = "This is the data I want you to store and please do not mindle with it at any cost!".to_string();
-    let len_message = message.split(" ").count();
+    /// Largest frame accepted for answers carrying chunks, in bytes
+    #[arg(long, global = true, default_value_t = Limits::default().max_chunk_frame)]
+    max_chunk_frame: usize,
 
-    // send the message to server
-    let stream_establish = TcpStream::connect("127.0.0.1:8080");
-    match stream_establish {
-        Ok(mut stream) => {
-            println!("Successfully connected to server in port 8080");
+    /// Largest frame accepted for any other answer, in bytes
+    #[arg(long, global = true, default_value_t = Limits::default().max_control_frame)]
+    max_control_frame: usize,
 
-            // send the message
-            send_message(&mut stream, message);
+    /// Most leaves an object, or chunks and hashes an answer, may have
+    #[arg(long, global = true, default_value_t = Limits::default().max_leaves)]
+    max_leaves: usize,
 
-            // receive the root from server
-            let merkle_root = receive_root(&mut stream);
-            print_root(merkle_root);
+    /// Most leaves one audit may challenge
+    #[arg(long, global = true, default_value_t = Limits::default().max_challenge)]
+    max_challenge: usize,
+}
 
-            // send chosen indices
-            let chosen_indices = compute_random_index(len_message);
-            let chosen_indices_clone = chosen_indices.clone();
-            send_indices(&mut stream, chosen_indices);
+impl LimitArgs {
+    fn limits(&self) -> Limits {
+        Limits {
+            max_store_frame: self.max_store_frame,
+            max_chunk_frame: self.max_chunk_frame,
+            max_control_frame: self.max_control_frame,
+            max_leaves: self.max_leaves,
+            max_challenge: self.max_challenge,
+        }
+    }
+}
 
-            // receive leaves to prove from server
-            let leaves_to_prove = receive_leaves_to_prove(&mut stream);
-            print_leaves(&leaves_to_prove);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Text,
+    Json,
+}
 
-            // receive the proof from server
-            let merkle_proof = receive_proof(&mut stream);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ChunkingMode {
+    Fixed,
+    ContentDefined,
+}
 
-            // verify proof
-            verify_merkle_proof(merkle_proof, merkle_root, chosen_indices_clone, &leaves_to_prove, len_message);
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Upload a file, recording the root computed locally
+    Upload {
+        file: PathBuf,
+        /// How the file is split into chunks
+        #[arg(long, value_enum, default_value_t = ChunkingMode::Fixed)]
+        chunking: ChunkingMode,
+        /// Size in bytes of the chunks, the average size for content-defined chunking
+        #[arg(long, default_value_t = DEFAULT_CHUNK_SIZE)]
+        chunk_size: usize,
+        /// Hash function of the tree: sha256, sha512-256, sha3-256, keccak256 or blake3
+        #[arg(long, default_value_t = HashAlgorithm::Sha256)]
+        hasher: HashAlgorithm,
+        /// Hash leaves and interior nodes alike, as objects stored before domain separation were
+        #[arg(long)]
+        no_domain_separation: bool,
+    },
+    /// Challenge the server to prove it still holds an object
+    Audit {
+        /// Object id or name
+        object: String,
+        /// Number of distinct random leaves to challenge [default: enough to meet --confidence]
+        #[arg(long)]
+        leaves: Option<usize>,
+        /// Smallest fraction of lost or altered chunks the audit must catch
+        #[arg(long, default_value_t = 0.01, value_parser = parse_fraction)]
+        corrupted_fraction: f64,
+        /// Probability with which that much damage must be detected
+        #[arg(long, default_value_t = 0.999, value_parser = parse_fraction)]
+        confidence: f64,
+        /// Bind the answer to a fresh random nonce so it cannot be precomputed
+        #[arg(long)]
+        nonce: bool,
+        /// Audit the earlier recorded version with this root, in hex, instead of the current one
+        #[arg(long)]
+        version: Option<String>,
+    },
+    /// Retrieve an object, verifying every chunk before writing it out
+    Download {
+        /// Object id or name
+        object: String,
+        /// Write the content here instead of to stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Only read the bytes from this offset on, under one multi-proof (fixed-size chunking only)
+        #[arg(long)]
+        offset: Option<usize>,
+        /// Only read this many bytes (fixed-size chunking only)
+        #[arg(long)]
+        length: Option<usize>,
+        /// Retrieve the earlier recorded version with this root, in hex, instead of the current one
+        #[arg(long)]
+        version: Option<String>,
+    },
+    /// Append the content of a file to an object, confirming the new root
+    Append {
+        /// Object id or name
+        object: String,
+        file: PathBuf,
+    },
+    /// Replace one chunk of an object with the content of a file, confirming the new root
+    Replace {
+        /// Object id or name
+        object: String,
+        /// Index of the chunk to replace
+        index: usize,
+        file: PathBuf,
+    },
+    /// Move an object to the server's current root if it provably only appended to the recorded one
+    Sync {
+        /// Object id or name
+        object: String,
+    },
+    /// Show the versions of an object the server retains
+    History {
+        /// Object id or name
+        object: String,
+    },
+    /// Make an earlier recorded version of an object current again
+    Rollback {
+        /// Object id or name
+        object: String,
+        /// Root of the version to return to, in hex
+        root: String,
+    },
+    /// Show the locally tracked objects
+    List,
+}
 
-        }
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(&cli) {
+        Ok(()) => ExitCode::SUCCESS,
         Err(e) => {
-            println!("Failed to connect: {}", e);
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
         }
     }
 }
 
-// ==================== HELPER FUNCTIONS SENDING ====================
+fn run(cli: &Cli) -> Result<()> {
+    let mut catalogue = Catalogue::load(&cli.catalogue)?;
+    match &cli.command {
+        Command::Upload { file, chunking, chunk_size, hasher, no_domain_separation } => {
+            let chunking = match chunking {
+                ChunkingMode::Fixed => Chunking::Fixed { size: *chunk_size },
+                ChunkingMode::ContentDefined => Chunking::content_defined(*chunk_size),
+            };
+            chunking.validate()?;
+            let hasher = TreeHasher {
+                domain_separated: !no_domain_separation,
+                ..TreeHasher::new(*hasher)
+            };
+            upload(cli, &mut catalogue, file, chunking, hasher)
+        }
+        Command::Audit { object, leaves, corrupted_fraction, confidence, nonce, version } => {
+            let entry = find_version(&catalogue, object, version.as_deref())?;
+            audit(cli, &entry, *leaves, *corrupted_fraction, *confidence, *nonce)
+        }
+        Command::Download { object, output, offset, length, version } => {
+            let entry = find_version(&catalogue, object, version.as_deref())?;
+            download(cli, &entry, output.as_deref(), *offset, *length)
+        }
+        Command::Append { object, file } => append(cli, &mut catalogue, object, file),
+        Command::Replace { object, index, file } => replace(cli, &mut catalogue, object, *index, file),
+        Command::Sync { object } => sync(cli, &mut catalogue, object),
+        Command::History { object } => history(cli, &catalogue, object),
+        Command::Rollback { object, root } => rollback(cli, &mut catalogue, object, root),
+        Command::List => list(cli, &catalogue),
+    }
+}
 
-fn send_message(stream: &mut TcpStream, message: String) {
-    let serialised_message = message.as_bytes();
-    let size_of_message: u32 = serialised_message.len() as u32;
+// ==================== COMMANDS ====================
+
+#[derive(Debug, Serialize)]
+struct UploadReport<'a> {
+    name: &'a str,
+    object_id: ObjectId,
+    #[serde(with = "hex::serde")]
+    root: [u8; 32],
+    leaf_count: usize,
+    chunking: Chunking,
+    hasher: String,
+}
 
-    let mut header = [0 as u8; 4];
-    header.copy_from_slice(&size_of_message.to_be_bytes());
+fn upload(cli: &Cli, catalogue: &mut Catalogue, file: &Path, chunking: Chunking, hasher: TreeHasher) -> Result<()> {
+    let data = fs::read(file)?;
+    let server = cli.server.clone().unwrap_or_else(|| DEFAULT_SERVER.to_string());
+
+    // refuse an upload the server would refuse before hashing or sending any of it
+    let limits = cli.limits.limits();
+    limits.check_store(data.len())?;
+
+    // the trusted root is computed here, before the data leaves the machine
+    let leaves = hash_leaves(hasher, &compute_leaves(&data, &chunking));
+    let leaf_count = leaves.len();
+    let root = compute_merkle_tree(hasher, &leaves)
+        .root()
+        .ok_or_else(|| Error::InvalidInput("cannot upload an empty file".to_string()))?;
+
+    let data_len = data.len();
+    let mut connection = session::connect(&server, limits)?;
+    let (object_id, server_root) = session::upload(&mut connection, chunking, hasher, data)?;
+    if server_root != root {
+        return Err(Error::VerificationFailed(format!(
+            "server reported root {} for object {}, but the file hashes to {}",
+            hex::encode(server_root),
+            object_id,
+            hex::encode(root)
+        )));
+    }
 
-    stream.write_all(&header).unwrap();
-    stream.write_all(&serialised_message).unwrap();
+    let name = file
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| file.display().to_string());
+    catalogue.add(CatalogueEntry {
+        name: name.clone(),
+        object_id,
+        root,
+        size: data_len,
+        leaf_count,
+        chunking,
+        hasher,
+        uploaded_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs()),
+        server,
+        history: Vec::new(),
+    });
+    catalogue.save()?;
+
+    let report = UploadReport { name: &name, object_id, root, leaf_count, chunking, hasher: hasher.to_string() };
+    print_report(cli.format, &report, || {
+        println!("Stored {} as object {}", name, object_id);
+        println!("Merkle root: {}", hex::encode(root));
+    })
 }
 
-fn send_indices(stream: &mut TcpStream, indices: Vec<usize>) {
-    // convert to string
-    let first_elem = indices[0].to_string();
-    let second_elem = indices[1].to_string();
-    let indices_string = first_elem + " " + &second_elem;
-    let indices_bytes = indices_string.as_bytes();
+#[derive(Debug, Serialize)]
+struct AuditReport {
+    object_id: ObjectId,
+    indices: Vec<usize>,
+    verified: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce_tags_verified: Option<bool>,
+    corrupted_fraction: f64,
+    /// Probability that this audit catches `corrupted_fraction` of damage.
+    confidence: f64,
+}
 
-    let size_of_indices: u32 = indices_bytes.len() as u32;
+fn audit(
+    cli: &Cli,
+    entry: &CatalogueEntry,
+    leaves: Option<usize>,
+    corrupted_fraction: f64,
+    confidence: f64,
+    nonce_bound: bool,
+) -> Result<()> {
+    let sample_size = leaves.unwrap_or_else(|| sampling::sample_size(entry.leaf_count, corrupted_fraction, confidence));
+
+    let mut connection = session::connect(server_for(cli, entry), cli.limits.limits())?;
+    let max_chunk_len = entry.chunking.max_chunk_len();
+    let audit = session::audit(&mut connection, &entry.trusted(), max_chunk_len, sample_size, nonce_bound)?;
+
+    let report = AuditReport {
+        object_id: entry.object_id,
+        confidence: sampling::detection_probability(entry.leaf_count, corrupted_fraction, audit.indices.len()),
+        indices: audit.indices,
+        verified: audit.verified,
+        nonce_tags_verified: audit.tags_verified,
+        corrupted_fraction,
+    };
+    print_report(cli.format, &report, || {
+        println!(
+            "Challenged {} of {} leaves of {}: {:?}",
+            report.indices.len(),
+            entry.leaf_count,
+            report.object_id,
+            report.indices
+        );
+        println!("Merkle Proof Result: {}", report.verified);
+        if let Some(tags_verified) = report.nonce_tags_verified {
+            println!("Nonce Tags Result: {}", tags_verified);
+        }
+        println!(
+            "Detection confidence: {:.4}% that {}% of the chunks being lost or altered would have been caught",
+            report.confidence * 100.0,
+            report.corrupted_fraction * 100.0
+        );
+    })?;
+
+    // the report is out either way, but a failed audit must fail the command
+    if !report.verified || report.nonce_tags_verified == Some(false) {
+        return Err(Error::VerificationFailed(format!(
+            "{} failed the audit, the server does not hold the challenged chunks as uploaded",
+            report.object_id
+        )));
+    }
+    Ok(())
+}
 
-    let mut header = [0 as u8; 4];
-    header.copy_from_slice(&size_of_indices.to_be_bytes());
+#[derive(Debug, Serialize)]
+struct DownloadReport {
+    object_id: ObjectId,
+    offset: usize,
+    bytes: usize,
+    chunks: usize,
+    output: PathBuf,
+}
 
-    stream.write_all(&header).unwrap();
-    stream.write_all(indices_bytes).unwrap();
+fn download(
+    cli: &Cli,
+    entry: &CatalogueEntry,
+    output: Option<&Path>,
+    offset: Option<usize>,
+    length: Option<usize>,
+) -> Result<()> {
+    let (offset, bytes, chunks) = match (offset, length) {
+        (None, None) => {
+            let bytes = download_all(cli, entry, output)?;
+            (0, bytes, entry.leaf_count)
+        }
+        (offset, length) => {
+            let offset = offset.unwrap_or(0);
+            let length = length.unwrap_or_else(|| entry.size.saturating_sub(offset));
+            let (bytes, chunks) = read_range(cli, entry, output, offset, length)?;
+            (offset, bytes, chunks)
+        }
+    };
+    let path = match output {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let report = DownloadReport { object_id: entry.object_id, offset, bytes, chunks, output: path.to_path_buf() };
+    print_report(cli.format, &report, || {
+        println!(
+            "Verified {} chunks of {} against its root and wrote {} bytes to {}",
+            report.chunks,
+            report.object_id,
+            report.bytes,
+            report.output.display()
+        );
+    })
 }
 
-// ==================== HELPER FUNCTIONS RECEIVING ====================
+/// Streams every chunk of the object, writing each one as soon as it verified.
+/// Returns the number of bytes written.
+fn download_all(cli: &Cli, entry: &CatalogueEntry, output: Option<&Path>) -> Result<usize> {
+    // verified chunks go to a temporary file that only becomes the output once all of them passed
+    let part_path = output.map(|path| {
+        let mut part = path.as_os_str().to_owned();
+        part.push(".part");
+        PathBuf::from(part)
+    });
+    let mut writer: Box<dyn Write> = match &part_path {
+        Some(path) => Box::new(io::BufWriter::new(fs::File::create(path)?)),
+        None => Box::new(io::stdout().lock()),
+    };
+
+    let mut connection = session::connect(server_for(cli, entry), cli.limits.limits())?;
+    let mut written = 0;
+    let result = session::download(&mut connection, &entry.trusted(), 0..entry.leaf_count, |_, data| {
+        written += data.len();
+        Ok(writer.write_all(data)?)
+    })
+    .and_then(|()| Ok(writer.flush()?));
+    drop(writer);
+
+    if let (Some(path), Some(part_path)) = (output, part_path) {
+        if let Err(e) = result {
+            let _ = fs::remove_file(&part_path);
+            return Err(e);
+        }
+        fs::rename(&part_path, path)?;
+    }
+    result.map(|()| written)
+}
 
-fn receive_root(stream: &mut TcpStream)  -> [u8; 32] {
-    let mut buffer = [0 as u8; 4];
-    stream.read_exact(&mut buffer).unwrap();
-    let size_of_root = u32::from_be_bytes(buffer);
+/// Fetches the chunks covering a byte range under one multi-proof and writes
+/// just the range; byte positions of chunks are only known up front for
+/// fixed-size chunking. Returns the number of bytes written and of chunks
+/// fetched.
+fn read_range(
+    cli: &Cli,
+    entry: &CatalogueEntry,
+    output: Option<&Path>,
+    offset: usize,
+    length: usize,
+) -> Result<(usize, usize)> {
+    let chunk_size = match entry.chunking {
+        Chunking::Fixed { size } => size,
+        Chunking::ContentDefined { .. } => {
+            return Err(Error::InvalidInput(
+                "byte ranges need fixed-size chunking, this object uses content-defined chunks".to_string(),
+            ))
+        }
+    };
+    if offset.checked_add(length).is_none_or(|end| length == 0 || end > entry.size) {
+        return Err(Error::InvalidInput(format!(
+            "{} bytes at offset {} are not within the {} bytes of {}",
+            length, offset, entry.size, entry.name
+        )));
+    }
 
-    let mut root_buffer = vec![0 as u8; size_of_root as usize];
-    stream.read_exact(&mut root_buffer).unwrap();
+    let mut connection = session::connect(server_for(cli, entry), cli.limits.limits())?;
+    let content = session::read_range(&mut connection, &entry.trusted(), chunk_size, offset, length)?;
+    match output {
+        Some(path) => fs::write(path, &content)?,
+        None => io::stdout().write_all(&content)?,
+    }
+    let chunks = covering_chunks(chunk_size, offset, length).map_or(0, |chunks| chunks.len());
+    Ok((content.len(), chunks))
+}
 
-    let slice = &root_buffer[..];
-    let root = <[u8; 32]>::try_from(slice).unwrap();
-    root
+#[derive(Debug, Serialize)]
+struct UpdateReport {
+    object_id: ObjectId,
+    #[serde(with = "hex::serde")]
+    old_root: [u8; 32],
+    #[serde(with = "hex::serde")]
+    root: [u8; 32],
+    leaf_count: usize,
+    size: usize,
 }
 
-fn receive_proof(stream: &mut TcpStream) -> MerkleProof<Sha256Algorithm> {
-    let mut buffer = [0 as u8; 4];
-    stream.read_exact(&mut buffer).unwrap();
-    let size_of_proof = u32::from_be_bytes(buffer);
+fn append(cli: &Cli, catalogue: &mut Catalogue, object: &str, file: &Path) -> Result<()> {
+    let mut entry = catalogue.find(object)?.clone();
+    let data = fs::read(file)?;
+    if let Chunking::Fixed { size } = entry.chunking {
+        if entry.size % size != 0 {
+            return Err(Error::InvalidInput(format!(
+                "the last chunk of {} is not full, appending would leave a short one in the middle",
+                entry.name
+            )));
+        }
+    }
+    let chunks: Vec<Vec<u8>> = compute_leaves(&data, &entry.chunking)
+        .into_iter()
+        .map(<[u8]>::to_vec)
+        .collect();
+    if chunks.is_empty() {
+        return Err(Error::InvalidInput("nothing to append".to_string()));
+    }
 
-    let mut proof_buffer = vec![0 as u8; size_of_proof as usize];
-    stream.read_exact(&mut proof_buffer).unwrap();
+    let added = chunks.len();
+    let mut connection = session::connect(server_for(cli, &entry), cli.limits.limits())?;
+    let root = session::append(&mut connection, &entry.trusted(), chunks)?;
 
-    let proof = MerkleProof::<Sha256Algorithm>::from_bytes(&proof_buffer).unwrap();
-    proof
+    let previous = entry.current_version();
+    entry.root = root;
+    entry.leaf_count += added;
+    entry.size += data.len();
+    record_update(cli, catalogue, entry, previous)
 }
 
-fn receive_leaves_to_prove(stream: &mut TcpStream) -> [[u8; 32]; 2] {
-    // FIRST LEAF
-    let mut buffer = [0 as u8; 4];
-    stream.read_exact(&mut buffer).unwrap();
-    let size_of_leaf = u32::from_be_bytes(buffer);
-
-    let mut leaf_buffer = vec![0 as u8; size_of_leaf as usize];
-    stream.read_exact(&mut leaf_buffer).unwrap();
+fn replace(cli: &Cli, catalogue: &mut Catalogue, object: &str, index: usize, file: &Path) -> Result<()> {
+    let mut entry = catalogue.find(object)?.clone();
+    let data = fs::read(file)?;
+    if index >= entry.leaf_count {
+        return Err(Error::InvalidInput(format!("{} has no chunk {}, only {}", entry.name, index, entry.leaf_count)));
+    }
+    entry
+        .chunking
+        .check_chunk_len(data.len(), index == entry.leaf_count - 1)?;
+
+    let new_len = data.len();
+    let mut connection = session::connect(server_for(cli, &entry), cli.limits.limits())?;
+    let (root, old_len) = session::replace(&mut connection, &entry.trusted(), index, data)?;
+
+    let previous = entry.current_version();
+    entry.root = root;
+    entry.size = (entry.size + new_len).saturating_sub(old_len);
+    record_update(cli, catalogue, entry, previous)
+}
 
-    let slice = &leaf_buffer[..];
-    // Convert &[u8] to [u8; 32]
-    let mut leaf: [u8; 32] = [0u8; 32];
-    leaf.copy_from_slice(slice);
-    // array
+fn sync(cli: &Cli, catalogue: &mut Catalogue, object: &str) -> Result<()> {
+    let mut entry = catalogue.find(object)?.clone();
+    let previous = entry.current_version();
+    let mut connection = session::connect(server_for(cli, &entry), cli.limits.limits())?;
+    let (root, leaf_count) = session::sync(&mut connection, &entry.trusted())?;
+
+    if root != entry.root {
+        // the new size follows from verified chunks: the last one for
+        // fixed-size chunks, all appended ones otherwise
+        let new_chunks = match entry.chunking {
+            Chunking::Fixed { .. } => leaf_count - 1..leaf_count,
+            Chunking::ContentDefined { .. } => entry.leaf_count..leaf_count,
+        };
+        let mut new_bytes = 0;
+        let current = TrustedVersion { root, leaf_count, ..entry.trusted() };
+        session::download(&mut connection, &current, new_chunks, |_, data| {
+            new_bytes += data.len();
+            Ok(())
+        })?;
+        entry.size = match entry.chunking {
+            Chunking::Fixed { size } => (leaf_count - 1) * size + new_bytes,
+            Chunking::ContentDefined { .. } => entry.size + new_bytes,
+        };
+    }
 
-    // SECOND LEAF
-    let mut buffer_2 = [0 as u8; 4];
-    stream.read_exact(&mut buffer_2).unwrap();
-    let size_of_leaf_2 = u32::from_be_bytes(buffer_2);
+    entry.root = root;
+    entry.leaf_count = leaf_count;
+    record_update(cli, catalogue, entry, previous)
+}
 
-    let mut leaf_buffer_2 = vec![0 as u8; size_of_leaf_2 as usize];
-    stream.read_exact(&mut leaf_buffer_2).unwrap();
+fn history(cli: &Cli, catalogue: &Catalogue, object: &str) -> Result<()> {
+    let entry = catalogue.find(object)?;
+    let mut connection = session::connect(server_for(cli, entry), cli.limits.limits())?;
+    let versions = session::list_versions(&mut connection, entry.object_id)?;
+
+    // only versions this client recorded itself can be checked against
+    let recorded: Vec<[u8; 32]> = entry.history.iter().map(|version| version.root).chain([entry.root]).collect();
+    let report: Vec<VersionReport> = versions
+        .iter()
+        .map(|version| VersionReport {
+            root: version.root,
+            leaf_count: version.leaf_count,
+            created_at: version.created_at,
+            recorded: recorded.contains(&version.root),
+            current: version.root == entry.root,
+        })
+        .collect();
+    print_report(cli.format, &report, || {
+        for version in &report {
+            println!(
+                "{}  {} leaves  stored {}{}{}",
+                hex::encode(version.root),
+                version.leaf_count,
+                version.created_at,
+                if version.recorded { "  recorded" } else { "" },
+                if version.current { "  current" } else { "" }
+            );
+        }
+    })
+}
 
-    let slice_2 = &leaf_buffer_2[..];
-    // Convert &[u8] to [u8; 32]
-    let mut leaf_2: [u8; 32] = [0u8; 32];
-    leaf_2.copy_from_slice(slice_2);
-    
-    // array: join leaf and leaf_2 of the type [[u8; 32]; 2]
-    let leaves_to_prove: [[u8; 32]; 2] = [leaf, leaf_2];
-    leaves_to_prove
+#[derive(Debug, Serialize)]
+struct VersionReport {
+    #[serde(with = "hex::serde")]
+    root: [u8; 32],
+    leaf_count: usize,
+    created_at: u64,
+    /// Whether the local catalogue recorded this version, so proofs against
+    /// it can be checked.
+    recorded: bool,
+    /// Whether this is the version the catalogue currently trusts.
+    current: bool,
 }
 
+fn rollback(cli: &Cli, catalogue: &mut Catalogue, object: &str, root: &str) -> Result<()> {
+    let mut entry = catalogue.find(object)?.clone();
+    let target = entry.at_version(root)?;
+    let previous = entry.current_version();
 
-// ==================== HELPER FUNCTIONS MERKLE ====================
+    let mut connection = session::connect(server_for(cli, &entry), cli.limits.limits())?;
+    session::rollback(&mut connection, entry.object_id, entry.root, target.root, target.leaf_count)?;
 
-#[allow(dead_code)]
-fn compute_merkle_tree(hashed_leaves: &Vec<[u8;32]>) -> MerkleTree<Sha256Algorithm> {
-    let merkle_tree = MerkleTree::<Sha256Algorithm>::from_leaves(&hashed_leaves);
-    merkle_tree
+    entry.root = target.root;
+    entry.leaf_count = target.leaf_count;
+    entry.size = target.size;
+    record_update(cli, catalogue, entry, previous)
 }
-#[allow(dead_code)]
-fn hash_leaves(leaves: Vec<&str>) -> Vec<[u8;32]> {
-    let leaves: Vec<[u8; 32]> = leaves
-    .iter()
-    .map(|x| Sha256Algorithm::hash(x.as_bytes()))
-    .collect();
 
-    leaves
-}
-#[allow(dead_code)]
-fn compute_leaves(message: &String) -> Vec<&str> {
-    let split_values: Vec<&str> = message.split(' ').collect();
-    let leaf_array: Vec<&str> = split_values.into_iter().collect();
+/// Records the version an update moved an object to, once the client has
+/// confirmed it, keeping the one before in the object's history.
+fn record_update(cli: &Cli, catalogue: &mut Catalogue, mut entry: CatalogueEntry, previous: PastVersion) -> Result<()> {
+    let report = UpdateReport {
+        object_id: entry.object_id,
+        old_root: previous.root,
+        root: entry.root,
+        leaf_count: entry.leaf_count,
+        size: entry.size,
+    };
+    if previous.root != entry.root {
+        entry.history.push(previous);
+    }
+    catalogue.add(entry);
+    catalogue.save()?;
 
-    leaf_array
+    print_report(cli.format, &report, || {
+        println!("Updated {}, now {} leaves and {} bytes", report.object_id, report.leaf_count, report.size);
+        println!("Merkle root: {} -> {}", hex::encode(report.old_root), hex::encode(report.root));
+    })
 }
 
-fn verify_merkle_proof(merkle_proof: MerkleProof<Sha256Algorithm>, merkle_root: [u8; 32], indices_to_prove: Vec<usize>, leaves_to_prove: &[<Sha256Algorithm as Hasher>::Hash], leaves_len: usize) {
-    let result = merkle_proof.verify(merkle_root, &indices_to_prove, leaves_to_prove, leaves_len);
-    println!("Merkle Proof Result: {}", result);
+fn list(cli: &Cli, catalogue: &Catalogue) -> Result<()> {
+    print_report(cli.format, catalogue.entries(), || {
+        for entry in catalogue.entries() {
+            println!(
+                "{}  {}  {} leaves ({})  {}  uploaded {}  {}  {}",
+                entry.object_id,
+                hex::encode(entry.root),
+                entry.leaf_count,
+                entry.chunking,
+                entry.hasher,
+                entry.uploaded_at,
+                entry.server,
+                entry.name
+            );
+        }
+    })
 }
 
-fn compute_random_index(len_message: usize) -> Vec<usize> {
-    let mut rng = rand::thread_rng();
-    let mut indices_to_prove: Vec<usize> = Vec::new();
-    let first = rng.gen_range(0..len_message-2);
-    let second = first + 1;
-    indices_to_prove.push(first);
-    indices_to_prove.push(second);
-    indices_to_prove
-}
+// ==================== HELPER FUNCTIONS ====================
 
-// ==================== PRINTING FUNCTIONS ====================
-#[allow(dead_code)]
-fn print_root(merkle_root: [u8; 32]) {
-    println!("Merkle root: {:?}", merkle_root);
-}
-#[allow(dead_code)]
-fn print_vertices(vertices: &Vec<usize>) {
-    for vertex in vertices {
-        print!("{}", vertex);
-        print!(" ");
+fn parse_fraction(value: &str) -> std::result::Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(fraction) if fraction > 0.0 && fraction < 1.0 => Ok(fraction),
+        Ok(_) => Err("must be strictly between 0 and 1".to_string()),
+        Err(e) => Err(e.to_string()),
     }
-    println!();
 }
-#[allow(dead_code)]
-fn print_leaves(leaves: &[<Sha256Algorithm as Hasher>::Hash]) {
-    for leaf in leaves {
-        print!("{:?}", leaf);
-        print!(" ");
+
+/// The catalogue entry of an object, as it was at an earlier recorded
+/// version if `version` names one by its root.
+fn find_version(catalogue: &Catalogue, object: &str, version: Option<&str>) -> Result<CatalogueEntry> {
+    let entry = catalogue.find(object)?;
+    match version {
+        Some(root) => entry.at_version(root),
+        None => Ok(entry.clone()),
     }
-    println!();
 }
 
+fn server_for<'a>(cli: &'a Cli, entry: &'a CatalogueEntry) -> &'a str {
+    cli.server.as_deref().unwrap_or(&entry.server)
+}
+
+fn print_report<T: Serialize + ?Sized>(format: Format, report: &T, text: impl FnOnce()) -> Result<()> {
+    match format {
+        Format::Text => text(),
+        Format::Json => println!("{}", serde_json::to_string_pretty(report).map_err(io::Error::from)?),
+    }
+    Ok(())
+}
@@ -0,0 +1,403 @@
+//! One function per request the client can make of a storage server.
+
+use std::net::TcpStream;
+use std::ops::Range;
+
+use merkle_storage_core::protocol::{
+    read_message, unexpected_message, write_message, Message, VersionInfo, PROTOCOL_VERSION,
+};
+use merkle_storage_core::{
+    compute_appended_root, compute_root, covering_chunks, hash_leaf, hash_leaves, nonce_tag, verify_consistency_proof,
+    verify_merkle_proof, Chunking, Error, Limits, ObjectId, Result, TreeHasher,
+};
+use rand::seq::index::sample;
+
+/// What the client trusts about one version of an object: the root it
+/// computed or confirmed itself, with the leaf count and hasher of the tree.
+#[derive(Debug, Clone, Copy)]
+pub struct TrustedVersion {
+    pub object_id: ObjectId,
+    pub root: [u8; 32],
+    pub leaf_count: usize,
+    pub hasher: TreeHasher,
+}
+
+impl TrustedVersion {
+    /// Refuses a proof the server declares to be for a tree hashed otherwise
+    /// than the one recorded for this object.
+    fn check_hasher(&self, declared: TreeHasher) -> Result<()> {
+        if declared == self.hasher {
+            return Ok(());
+        }
+        Err(Error::VerificationFailed(format!(
+            "server sent a proof for {} hashed with {}, but it was stored with {}",
+            self.object_id, declared, self.hasher
+        )))
+    }
+}
+
+/// A connection to a storage server and the limits its answers are held to.
+pub struct Connection {
+    stream: TcpStream,
+    limits: Limits,
+}
+
+impl Connection {
+    /// Sends a request. When the write fails because the server hung up on
+    /// it, the `Error` frame it answered with, if any, is reported instead.
+    fn send(&mut self, message: &Message) -> Result<()> {
+        match write_message(&mut self.stream, message) {
+            Err(Error::Io(e)) => match read_message(&mut self.stream, &self.limits) {
+                Ok(Message::Error { kind, message }) => Err(Error::Rejected { kind, message }),
+                _ => Err(Error::Io(e)),
+            },
+            sent => sent,
+        }
+    }
+
+    fn receive(&mut self) -> Result<Message> {
+        read_message(&mut self.stream, &self.limits)
+    }
+}
+
+pub fn connect(address: &str, limits: Limits) -> Result<Connection> {
+    let mut connection = Connection { stream: TcpStream::connect(address)?, limits };
+
+    // agree on the protocol version
+    connection.send(&Message::Hello { version: PROTOCOL_VERSION })?;
+    match connection.receive()? {
+        Message::HelloAck { version } if version == PROTOCOL_VERSION => Ok(connection),
+        Message::HelloAck { version } => Err(Error::Protocol(format!(
+            "server answered with protocol version {}, this client speaks version {}",
+            version, PROTOCOL_VERSION
+        ))),
+        other => Err(unexpected_message("HelloAck", other)),
+    }
+}
+
+pub fn upload(
+    connection: &mut Connection,
+    chunking: Chunking,
+    hasher: TreeHasher,
+    data: Vec<u8>,
+) -> Result<(ObjectId, [u8; 32])> {
+    // send the data
+    connection.send(&Message::Store { chunking, hasher, data })?;
+
+    // receive the object id and the root from server
+    match connection.receive()? {
+        Message::RootAck { object_id, root } => Ok((object_id, root)),
+        other => Err(unexpected_message("RootAck", other)),
+    }
+}
+
+pub struct Audit {
+    pub indices: Vec<usize>,
+    pub verified: bool,
+    /// Whether the nonce tags matched, for nonce-bound challenges.
+    pub tags_verified: Option<bool>,
+}
+
+/// Challenges `sample_size` random leaves of an object whose chunks are at
+/// most `max_chunk_len` bytes. A sample whose answer would not fit one chunk
+/// frame is split into several challenges, each with its own proof and
+/// nonce; the audit only verifies if all of them do.
+pub fn audit(
+    connection: &mut Connection,
+    trusted: &TrustedVersion,
+    max_chunk_len: usize,
+    sample_size: usize,
+    nonce_bound: bool,
+) -> Result<Audit> {
+    if sample_size == 0 {
+        return Err(Error::InvalidInput("an audit must challenge at least one leaf".to_string()));
+    }
+    let batch_size = connection.limits.max_challenge_for(max_chunk_len, trusted.leaf_count);
+    if batch_size == 0 {
+        return Err(Error::OutOfRange(format!(
+            "chunks of up to {} bytes of {} do not fit a frame of {} bytes with their proof",
+            max_chunk_len, trusted.object_id, connection.limits.max_chunk_frame
+        )));
+    }
+
+    let chosen_indices = compute_random_indices(trusted.leaf_count, sample_size);
+    let mut verified = true;
+    let mut tags_verified = nonce_bound.then_some(true);
+    for batch in chosen_indices.chunks(batch_size) {
+        let (batch_verified, batch_tags_verified) = challenge(connection, trusted, batch, nonce_bound)?;
+        verified &= batch_verified;
+        tags_verified = tags_verified.zip(batch_tags_verified).map(|(all, batch)| all && batch);
+    }
+    Ok(Audit { indices: chosen_indices, verified, tags_verified })
+}
+
+/// Challenges the given leaves at once, returning whether the proof and the
+/// nonce tags, if bound to a nonce, verified.
+fn challenge(
+    connection: &mut Connection,
+    trusted: &TrustedVersion,
+    indices: &[usize],
+    nonce_bound: bool,
+) -> Result<(bool, Option<bool>)> {
+    let TrustedVersion { object_id, root: trusted_root, leaf_count, hasher } = *trusted;
+
+    // send chosen indices, with a fresh nonce the answer has to be bound to
+    let nonce = nonce_bound.then(rand::random::<[u8; 32]>);
+    connection.send(&Message::Challenge { object_id, root: trusted_root, indices: indices.to_vec(), nonce })?;
+
+    // receive the challenged chunks and the proof from server
+    let (declared, chunks, proof_hashes, tags) = match connection.receive()? {
+        Message::ChallengeResponse { hasher, chunks, proof, tags } => (hasher, chunks, proof, tags),
+        other => return Err(unexpected_message("ChallengeResponse", other)),
+    };
+    trusted.check_hasher(declared)?;
+
+    // hash the chunks here: only data the server still holds can produce these leaves
+    let chunk_refs: Vec<&[u8]> = chunks.iter().map(Vec::as_slice).collect();
+    let leaves_to_prove = hash_leaves(hasher, &chunk_refs);
+
+    // verify proof against the root from the catalogue, never one from the server
+    let verified = verify_merkle_proof(hasher, &proof_hashes, trusted_root, indices, &leaves_to_prove, leaf_count);
+    let tags_verified = nonce.map(|nonce| {
+        tags.len() == chunk_refs.len()
+            && chunk_refs.iter().zip(&tags).all(|(chunk, tag)| nonce_tag(&nonce, chunk) == *tag)
+    });
+    Ok((verified, tags_verified))
+}
+
+/// Streams chunks `chunks` of an object, handing each one to `on_chunk` only
+/// once it has been verified against the trusted root. Stops at the first
+/// chunk that fails, naming it.
+pub fn download<F>(
+    connection: &mut Connection,
+    trusted: &TrustedVersion,
+    chunks: Range<usize>,
+    mut on_chunk: F,
+) -> Result<()>
+where
+    F: FnMut(usize, &[u8]) -> Result<()>,
+{
+    let TrustedVersion { object_id, root: trusted_root, leaf_count, hasher } = *trusted;
+    connection.send(&Message::Download { object_id, root: trusted_root, start: chunks.start, end: chunks.end })?;
+
+    for expected_index in chunks {
+        let (declared, index, data, proof_hashes) = match connection.receive()? {
+            Message::DownloadChunk { hasher, index, data, proof } => (hasher, index, data, proof),
+            other => return Err(unexpected_message("DownloadChunk", other)),
+        };
+        trusted.check_hasher(declared)?;
+        let leaf = hash_leaf(hasher, &data);
+        if index != expected_index
+            || !verify_merkle_proof(hasher, &proof_hashes, trusted_root, &[expected_index], &[leaf], leaf_count)
+        {
+            return Err(Error::VerificationFailed(format!(
+                "chunk {} of {} does not match the trusted root",
+                expected_index, object_id
+            )));
+        }
+        on_chunk(index, &data)?;
+    }
+    Ok(())
+}
+
+/// Reads `length` bytes from `offset` of an object cut into fixed-size chunks
+/// of `chunk_size` bytes. The covering chunks come with one multi-proof and
+/// are verified against the trusted root before they are trimmed to the range.
+/// A range whose chunks would not fit one chunk frame is read in several
+/// parts, each under its own multi-proof.
+pub fn read_range(
+    connection: &mut Connection,
+    trusted: &TrustedVersion,
+    chunk_size: usize,
+    offset: usize,
+    length: usize,
+) -> Result<Vec<u8>> {
+    let TrustedVersion { object_id, leaf_count, .. } = *trusted;
+    covering_chunks(chunk_size, offset, length).filter(|chunks| chunks.end <= leaf_count).ok_or_else(|| {
+        Error::OutOfRange(format!("{} bytes at offset {} are not within {}", length, offset, object_id))
+    })?;
+    let batch_size = connection.limits.max_read_for(chunk_size, leaf_count);
+    if batch_size == 0 {
+        return Err(Error::OutOfRange(format!(
+            "chunks of {} bytes of {} do not fit a frame of {} bytes with their proof",
+            chunk_size, object_id, connection.limits.max_chunk_frame
+        )));
+    }
+
+    // each part runs to the end of the last chunk one answer can carry
+    let end = offset + length;
+    let mut content = Vec::new();
+    let mut start = offset;
+    while start < end {
+        let part_end = (start / chunk_size + batch_size).saturating_mul(chunk_size).min(end);
+        content.extend(read_chunks(connection, trusted, chunk_size, start, part_end - start)?);
+        start = part_end;
+    }
+    Ok(content)
+}
+
+/// Reads a byte range whose covering chunks fit one answer.
+fn read_chunks(
+    connection: &mut Connection,
+    trusted: &TrustedVersion,
+    chunk_size: usize,
+    offset: usize,
+    length: usize,
+) -> Result<Vec<u8>> {
+    let TrustedVersion { object_id, root: trusted_root, leaf_count, hasher } = *trusted;
+    let expected = covering_chunks(chunk_size, offset, length).ok_or_else(|| {
+        Error::OutOfRange(format!("{} bytes at offset {} are not within {}", length, offset, object_id))
+    })?;
+    connection.send(&Message::ReadRange { object_id, root: trusted_root, offset, length })?;
+
+    let (declared, first_index, chunks, proof_hashes) = match connection.receive()? {
+        Message::ReadRangeResponse { hasher, first_index, chunks, proof } => (hasher, first_index, chunks, proof),
+        other => return Err(unexpected_message("ReadRangeResponse", other)),
+    };
+    trusted.check_hasher(declared)?;
+    let mismatch =
+        || Error::VerificationFailed(format!("chunks {:?} of {} do not match the trusted root", expected, object_id));
+    if first_index != expected.start || chunks.len() != expected.len() {
+        return Err(mismatch());
+    }
+
+    // hash the chunks here and check them all against the catalogue root
+    let chunk_refs: Vec<&[u8]> = chunks.iter().map(Vec::as_slice).collect();
+    let leaves = hash_leaves(hasher, &chunk_refs);
+    let indices: Vec<usize> = expected.clone().collect();
+    if !verify_merkle_proof(hasher, &proof_hashes, trusted_root, &indices, &leaves, leaf_count) {
+        return Err(mismatch());
+    }
+
+    // only now cut the range out of the verified chunks
+    let skip = offset - expected.start * chunk_size;
+    let content = chunk_refs.concat();
+    content.get(skip..skip + length).map(<[u8]>::to_vec).ok_or_else(|| {
+        Error::OutOfRange(format!("{} bytes at offset {} run past the end of {}", length, offset, object_id))
+    })
+}
+
+/// Appends chunks to an object and returns its new root. The client derives
+/// that root itself from the old last leaf, once that leaf is proven against
+/// the trusted root, and only accepts the server's root if it agrees.
+pub fn append(connection: &mut Connection, trusted: &TrustedVersion, chunks: Vec<Vec<u8>>) -> Result<[u8; 32]> {
+    let TrustedVersion { object_id, root: trusted_root, leaf_count, hasher } = *trusted;
+    let chunk_refs: Vec<&[u8]> = chunks.iter().map(Vec::as_slice).collect();
+    let new_leaves = hash_leaves(hasher, &chunk_refs);
+    connection.send(&Message::AppendChunks { object_id, root: trusted_root, chunks })?;
+
+    let (declared, last_leaf, proof_hashes, server_root) = match connection.receive()? {
+        Message::AppendAck { hasher, last_leaf, proof, root } => (hasher, last_leaf, proof, root),
+        other => return Err(unexpected_message("AppendAck", other)),
+    };
+    trusted.check_hasher(declared)?;
+    let last = leaf_count - 1;
+    if !verify_merkle_proof(hasher, &proof_hashes, trusted_root, &[last], &[last_leaf], leaf_count) {
+        return Err(Error::VerificationFailed(format!(
+            "proof of the last chunk of {} does not match the trusted root",
+            object_id
+        )));
+    }
+    let new_root = compute_appended_root(hasher, &proof_hashes, last_leaf, leaf_count, &new_leaves);
+    check_new_root(object_id, new_root, server_root)
+}
+
+/// Replaces chunk `index` of an object. Returns the new root, derived by the
+/// client from the old chunk proven against the trusted root, and the length
+/// of the old chunk.
+pub fn replace(
+    connection: &mut Connection,
+    trusted: &TrustedVersion,
+    index: usize,
+    data: Vec<u8>,
+) -> Result<([u8; 32], usize)> {
+    let TrustedVersion { object_id, root: trusted_root, leaf_count, hasher } = *trusted;
+    let new_leaf = hash_leaf(hasher, &data);
+    connection.send(&Message::ReplaceChunk { object_id, root: trusted_root, index, data })?;
+
+    let (declared, old_chunk, proof_hashes, server_root) = match connection.receive()? {
+        Message::ReplaceAck { hasher, old_chunk, proof, root } => (hasher, old_chunk, proof, root),
+        other => return Err(unexpected_message("ReplaceAck", other)),
+    };
+    trusted.check_hasher(declared)?;
+    let old_leaf = hash_leaf(hasher, &old_chunk);
+    if !verify_merkle_proof(hasher, &proof_hashes, trusted_root, &[index], &[old_leaf], leaf_count) {
+        return Err(Error::VerificationFailed(format!(
+            "proof of chunk {} of {} does not match the trusted root",
+            index, object_id
+        )));
+    }
+    let new_root = compute_root(hasher, &proof_hashes, &[index], &[new_leaf], leaf_count);
+    Ok((check_new_root(object_id, new_root, server_root)?, old_chunk.len()))
+}
+
+/// Asks for the current root of an object and accepts it, with its leaf
+/// count, only if it provably extends the trusted root of `leaf_count` leaves.
+pub fn sync(connection: &mut Connection, trusted: &TrustedVersion) -> Result<([u8; 32], usize)> {
+    let TrustedVersion { object_id, root: trusted_root, leaf_count, hasher } = *trusted;
+    connection.send(&Message::ProveConsistency { object_id, old_leaf_count: leaf_count })?;
+    let (declared, new_leaf_count, new_root, proof) = match connection.receive()? {
+        Message::ConsistencyProof { hasher, leaf_count, root, proof } => (hasher, leaf_count, root, proof),
+        other => return Err(unexpected_message("ConsistencyProof", other)),
+    };
+    trusted.check_hasher(declared)?;
+    connection.limits.check_leaves(new_leaf_count)?;
+    if !verify_consistency_proof(hasher, &proof, trusted_root, leaf_count, new_root, new_leaf_count) {
+        return Err(Error::VerificationFailed(format!(
+            "root {} of {} does not extend the trusted root",
+            hex::encode(new_root),
+            object_id
+        )));
+    }
+    Ok((new_root, new_leaf_count))
+}
+
+pub fn list_versions(connection: &mut Connection, object_id: ObjectId) -> Result<Vec<VersionInfo>> {
+    connection.send(&Message::ListVersions { object_id })?;
+    match connection.receive()? {
+        Message::Versions { versions } => Ok(versions),
+        other => Err(unexpected_message("Versions", other)),
+    }
+}
+
+/// Makes an earlier version of an object, which the client knows by its
+/// root and leaf count, current again.
+pub fn rollback(
+    connection: &mut Connection,
+    object_id: ObjectId,
+    trusted_root: [u8; 32],
+    target_root: [u8; 32],
+    target_leaf_count: usize,
+) -> Result<()> {
+    connection.send(&Message::Rollback { object_id, root: trusted_root, target: target_root })?;
+    match connection.receive()? {
+        Message::RollbackAck { root, leaf_count } if root == target_root && leaf_count == target_leaf_count => Ok(()),
+        Message::RollbackAck { root, leaf_count } => Err(Error::VerificationFailed(format!(
+            "server rolled {} back to root {} with {} leaves instead of the recorded version",
+            object_id,
+            hex::encode(root),
+            leaf_count
+        ))),
+        other => Err(unexpected_message("RollbackAck", other)),
+    }
+}
+
+fn check_new_root(object_id: ObjectId, expected: Option<[u8; 32]>, server_root: [u8; 32]) -> Result<[u8; 32]> {
+    match expected {
+        Some(root) if root == server_root => Ok(root),
+        _ => Err(Error::VerificationFailed(format!(
+            "server reported new root {} for {}, which does not follow from the proof",
+            hex::encode(server_root),
+            object_id
+        ))),
+    }
+}
+
+/// Picks `sample_size` distinct leaves at random (all of them for objects
+/// with fewer leaves), in ascending order as the multi-proof expects.
+fn compute_random_indices(leaf_count: usize, sample_size: usize) -> Vec<usize> {
+    let mut rng = rand::thread_rng();
+    let mut indices = sample(&mut rng, leaf_count, sample_size.min(leaf_count)).into_vec();
+    indices.sort_unstable();
+    indices
+}
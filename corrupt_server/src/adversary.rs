@@ -0,0 +1,158 @@
+//! The attacks the corrupt server can mount, as a [`Behaviour`] on top of the
+//! honest session logic. Each one strikes with a configurable probability,
+//! per chunk for attacks on chunks and per answer otherwise.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use clap::ValueEnum;
+use log::info;
+use merkle_storage_core::protocol::Message;
+use merkle_storage_core::{hash_leaf, ErrorKind, TreeHasher};
+use server::behaviour::Behaviour;
+use server::store::Store;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Attack {
+    /// Flip one random bit of chunks sent out
+    FlipBits,
+    /// Leave chunks out of answers, as if they were lost
+    DropChunks,
+    /// Answer reads from the oldest retained version instead of the one asked for
+    StaleVersions,
+    /// Report random roots after uploads and updates
+    ForgedRoot,
+    /// Answer with the proof sent for the previous request of the same kind
+    ReplayProofs,
+    /// Send leaf hashes in place of chunks, as a server that kept only those would
+    HashesOnly,
+    /// Answer with an error instead
+    RandomFailure,
+}
+
+pub struct Adversary {
+    attacks: Vec<Attack>,
+    probability: f64,
+    /// Last proof-carrying answer of each kind, for replays.
+    sent: Mutex<HashMap<&'static str, Message>>,
+}
+
+impl Adversary {
+    pub fn new(attacks: Vec<Attack>, probability: f64) -> Adversary {
+        Adversary { attacks, probability, sent: Mutex::new(HashMap::new()) }
+    }
+
+    fn mounts(&self, attack: Attack) -> bool {
+        self.attacks.contains(&attack)
+    }
+
+    /// Whether `attack` is mounted and strikes this time.
+    fn strikes(&self, attack: Attack) -> bool {
+        self.mounts(attack) && rand::random::<f64>() < self.probability
+    }
+
+    fn replay(&self, response: Message) -> Message {
+        let mut sent = self.sent.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let previous = sent.insert(response.name(), response.clone());
+        match previous {
+            Some(previous) if self.strikes(Attack::ReplayProofs) => {
+                info!("Replaying an earlier {}", previous.name());
+                previous
+            }
+            _ => response,
+        }
+    }
+
+    fn forge_root(&self, root: &mut [u8; 32]) {
+        if self.strikes(Attack::ForgedRoot) {
+            *root = rand::random();
+            info!("Forging root {}", hex::encode(root));
+        }
+    }
+
+    /// Tampers with the content of one chunk sent out.
+    fn tamper_chunk(&self, hasher: TreeHasher, chunk: &mut Vec<u8>) {
+        if self.strikes(Attack::HashesOnly) {
+            *chunk = hash_leaf(hasher, chunk).to_vec();
+            info!("Sending leaf {} in place of its chunk", hex::encode(&chunk));
+        }
+        if !chunk.is_empty() && self.strikes(Attack::FlipBits) {
+            let bit = rand::random::<usize>() % (chunk.len() * 8);
+            chunk[bit / 8] ^= 1 << (bit % 8);
+            info!("Flipping bit {} of a chunk", bit);
+        }
+    }
+
+    /// Tampers with the chunks of one answer; dropped ones are left out.
+    fn tamper_chunks(&self, hasher: TreeHasher, chunks: &mut Vec<Vec<u8>>) {
+        let before = chunks.len();
+        chunks.retain(|_| !self.strikes(Attack::DropChunks));
+        if chunks.len() < before {
+            info!("Dropping {} of {} chunks", before - chunks.len(), before);
+        }
+        for chunk in chunks {
+            self.tamper_chunk(hasher, chunk);
+        }
+    }
+}
+
+impl Behaviour for Adversary {
+    fn request(&self, store: &Store, mut request: Message) -> Message {
+        if let Message::Challenge { object_id, root, .. }
+        | Message::Download { object_id, root, .. }
+        | Message::ReadRange { object_id, root, .. } = &mut request
+        {
+            if self.strikes(Attack::StaleVersions) {
+                if let Ok(Some(object)) = store.get(object_id) {
+                    if let Some((oldest, _, _)) = object.versions().next().filter(|(oldest, _, _)| *oldest != root) {
+                        info!("Serving version {} of {} instead", hex::encode(oldest), object_id);
+                        *root = *oldest;
+                    }
+                }
+            }
+        }
+        request
+    }
+
+    fn response(&self, response: Message) -> Option<Message> {
+        if self.strikes(Attack::RandomFailure) {
+            info!("Failing instead of sending {}", response.name());
+            return Some(Message::Error { kind: ErrorKind::Io, message: "simulated failure".to_string() });
+        }
+
+        let mut response = match response {
+            Message::ChallengeResponse { .. }
+            | Message::DownloadChunk { .. }
+            | Message::ReadRangeResponse { .. }
+            | Message::AppendAck { .. }
+            | Message::ReplaceAck { .. }
+            | Message::ConsistencyProof { .. }
+                if self.mounts(Attack::ReplayProofs) =>
+            {
+                self.replay(response)
+            }
+            other => other,
+        };
+        match &mut response {
+            Message::ChallengeResponse { hasher, chunks, .. } | Message::ReadRangeResponse { hasher, chunks, .. } => {
+                self.tamper_chunks(*hasher, chunks)
+            }
+            Message::DownloadChunk { hasher, data, .. } => {
+                // a chunk streamed on its own cannot be left out without
+                // stalling the stream, so a dropped one is sent empty
+                if self.strikes(Attack::DropChunks) {
+                    info!("Dropping a chunk of {} bytes", data.len());
+                    data.clear();
+                }
+                self.tamper_chunk(*hasher, data)
+            }
+            Message::RootAck { root, .. }
+            | Message::AppendAck { root, .. }
+            | Message::ReplaceAck { root, .. }
+            | Message::ConsistencyProof { root, .. }
+            | Message::RollbackAck { root, .. } => self.forge_root(root),
+            _ => {}
+        }
+        Some(response)
+    }
+}
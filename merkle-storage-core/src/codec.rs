@@ -0,0 +1,293 @@
+//! Length-prefixed framing used on the wire, plus the primitive encoder and
+//! decoder the protocol messages are built from. Every frame is a 4-byte
+//! big-endian length followed by that many bytes.
+
+use std::io::{Read, Write};
+
+use crate::error::{Error, Result};
+use crate::object::ObjectId;
+
+const HEADER_SIZE: usize = 4;
+
+pub fn write_frame<W: Write>(stream: &mut W, payload: &[u8]) -> Result<()> {
+    let size_of_payload = u32::try_from(payload.len())
+        .map_err(|_| Error::OutOfRange(format!("a frame of {} bytes does not fit the header", payload.len())))?;
+
+    stream.write_all(&size_of_payload.to_be_bytes())?;
+    stream.write_all(payload)?;
+    Ok(())
+}
+
+/// Reads one frame. `check_len` gets the first byte of the payload and the
+/// length the header announces before the rest is allocated, so an oversized
+/// frame is refused without reading it.
+pub fn read_frame<R, F>(stream: &mut R, check_len: F) -> Result<Vec<u8>>
+where
+    R: Read,
+    F: FnOnce(u8, usize) -> Result<()>,
+{
+    let mut header = [0u8; HEADER_SIZE];
+    stream.read_exact(&mut header)?;
+    let size_of_payload = u32::from_be_bytes(header) as usize;
+    if size_of_payload == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut first = [0u8; 1];
+    stream.read_exact(&mut first)?;
+    check_len(first[0], size_of_payload)?;
+
+    let mut buffer = vec![0u8; size_of_payload];
+    buffer[0] = first[0];
+    stream.read_exact(&mut buffer[1..])?;
+    Ok(buffer)
+}
+
+/// Async counterpart of [`write_frame`].
+#[cfg(feature = "tokio")]
+pub async fn write_frame_async<W>(stream: &mut W, payload: &[u8]) -> Result<()>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::AsyncWriteExt;
+
+    let size_of_payload = u32::try_from(payload.len())
+        .map_err(|_| Error::OutOfRange(format!("a frame of {} bytes does not fit the header", payload.len())))?;
+
+    stream.write_all(&size_of_payload.to_be_bytes()).await?;
+    stream.write_all(payload).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Async counterpart of [`read_frame`].
+#[cfg(feature = "tokio")]
+pub async fn read_frame_async<R, F>(stream: &mut R, check_len: F) -> Result<Vec<u8>>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    F: FnOnce(u8, usize) -> Result<()>,
+{
+    use tokio::io::AsyncReadExt;
+
+    let mut header = [0u8; HEADER_SIZE];
+    stream.read_exact(&mut header).await?;
+    let size_of_payload = u32::from_be_bytes(header) as usize;
+    if size_of_payload == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut first = [0u8; 1];
+    stream.read_exact(&mut first).await?;
+    check_len(first[0], size_of_payload)?;
+
+    let mut buffer = vec![0u8; size_of_payload];
+    buffer[0] = first[0];
+    stream.read_exact(&mut buffer[1..]).await?;
+    Ok(buffer)
+}
+
+pub(crate) fn decode_error<E: ToString>(error: E) -> Error {
+    Error::Decode(error.to_string())
+}
+
+// ==================== ENCODER ====================
+
+#[derive(Debug, Default)]
+pub struct Encoder {
+    buffer: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put_u8(&mut self, value: u8) -> &mut Self {
+        self.buffer.push(value);
+        self
+    }
+
+    pub fn put_u32(&mut self, value: u32) -> &mut Self {
+        self.buffer.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn put_u64(&mut self, value: u64) -> &mut Self {
+        self.buffer.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn put_object_id(&mut self, object_id: &ObjectId) -> &mut Self {
+        self.buffer.extend_from_slice(&object_id.0);
+        self
+    }
+
+    pub fn put_hash(&mut self, hash: &[u8; 32]) -> &mut Self {
+        self.buffer.extend_from_slice(hash);
+        self
+    }
+
+    /// Variable-length byte strings carry their own u32 length.
+    pub fn put_bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.put_u32(bytes.len() as u32);
+        self.buffer.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn put_str(&mut self, value: &str) -> &mut Self {
+        self.put_bytes(value.as_bytes())
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buffer
+    }
+}
+
+// ==================== DECODER ====================
+
+#[derive(Debug)]
+pub struct Decoder<'a> {
+    buffer: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buffer: &'a [u8]) -> Self {
+        Self { buffer }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buffer.len() < len {
+            return Err(decode_error(format!(
+                "truncated frame: needed {} more bytes, {} left",
+                len,
+                self.buffer.len()
+            )));
+        }
+        let (head, rest) = self.buffer.split_at(len);
+        self.buffer = rest;
+        Ok(head)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8> {
+        self.take_array().map(u8::from_be_bytes)
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32> {
+        self.take_array().map(u32::from_be_bytes)
+    }
+
+    pub fn get_u64(&mut self) -> Result<u64> {
+        self.take_array().map(u64::from_be_bytes)
+    }
+
+    pub fn get_object_id(&mut self) -> Result<ObjectId> {
+        self.take_array().map(ObjectId)
+    }
+
+    pub fn get_hash(&mut self) -> Result<[u8; 32]> {
+        self.take_array()
+    }
+
+    pub fn get_bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.get_u32()? as usize;
+        self.take(len)
+    }
+
+    pub fn get_str(&mut self) -> Result<String> {
+        let bytes = self.get_bytes()?;
+        String::from_utf8(bytes.to_vec()).map_err(decode_error)
+    }
+
+    /// Fails if the frame carries bytes the message did not consume.
+    pub fn finish(self) -> Result<()> {
+        if self.buffer.is_empty() {
+            Ok(())
+        } else {
+            Err(decode_error(format!(
+                "{} trailing bytes after message",
+                self.buffer.len()
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip() {
+        let mut wire = Vec::new();
+        write_frame(&mut wire, b"\x10payload").unwrap();
+        write_frame(&mut wire, b"").unwrap();
+        assert_eq!(&wire[..4], &8u32.to_be_bytes());
+
+        let mut stream = wire.as_slice();
+        let mut checked = None;
+        let frame = read_frame(&mut stream, |tag, len| {
+            checked = Some((tag, len));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(frame, b"\x10payload");
+        assert_eq!(checked, Some((0x10, 8)));
+        assert!(read_frame(&mut stream, |_, _| panic!("an empty frame has no tag")).unwrap().is_empty());
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn oversized_frames_are_refused_before_they_are_read() {
+        // the header announces 4 GiB, of which only the tag ever arrives
+        let wire = [0xff, 0xff, 0xff, 0xff, 0x10];
+        let refused = read_frame(&mut wire.as_slice(), |_, len| {
+            Err(Error::OutOfRange(format!("{} bytes", len)))
+        });
+        assert!(matches!(refused, Err(Error::OutOfRange(message)) if message == "4294967295 bytes"));
+    }
+
+    #[test]
+    fn truncated_frames_fail() {
+        let wire = [0, 0, 0, 8, 0x10, 1, 2];
+        assert!(matches!(read_frame(&mut wire.as_slice(), |_, _| Ok(())), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let mut encoder = Encoder::new();
+        encoder
+            .put_u8(7)
+            .put_u32(0xdead_beef)
+            .put_u64(u64::MAX)
+            .put_object_id(&ObjectId([3; 16]))
+            .put_hash(&[9; 32])
+            .put_bytes(b"chunk")
+            .put_str("message");
+        let buffer = encoder.finish();
+
+        let mut decoder = Decoder::new(&buffer);
+        assert_eq!(decoder.get_u8().unwrap(), 7);
+        assert_eq!(decoder.get_u32().unwrap(), 0xdead_beef);
+        assert_eq!(decoder.get_u64().unwrap(), u64::MAX);
+        assert_eq!(decoder.get_object_id().unwrap(), ObjectId([3; 16]));
+        assert_eq!(decoder.get_hash().unwrap(), [9; 32]);
+        assert_eq!(decoder.get_bytes().unwrap(), b"chunk");
+        assert_eq!(decoder.get_str().unwrap(), "message");
+        decoder.finish().unwrap();
+    }
+
+    #[test]
+    fn decoding_past_the_end_or_short_of_it_fails() {
+        let mut decoder = Decoder::new(&[0, 0, 0, 9, 1, 2]);
+        assert!(matches!(decoder.get_bytes(), Err(Error::Decode(_))));
+
+        let mut decoder = Decoder::new(&[1, 2, 3]);
+        decoder.get_u8().unwrap();
+        assert!(matches!(decoder.finish(), Err(Error::Decode(_))));
+    }
+}
@@ -0,0 +1,116 @@
+//! Errors of both peers. A server that fails a request sends the error back
+//! in an `Error` frame carrying its [`ErrorKind`], so the client can tell a
+//! missing object from a malformed request or a failed proof.
+
+use std::fmt;
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The connection or the disk failed.
+    Io(io::Error),
+    /// A frame could not be decoded into a message.
+    Decode(String),
+    /// The peer sent a message that does not fit the session.
+    Protocol(String),
+    /// A request or argument is malformed.
+    InvalidInput(String),
+    /// An object, version or chunk does not exist.
+    NotFound(String),
+    /// An index, range or count lies outside the object or outside a limit.
+    OutOfRange(String),
+    /// An update names another version than the current one.
+    Conflict(String),
+    /// A proof, root or hasher did not check out.
+    VerificationFailed(String),
+    /// The peer refused the request with an `Error` frame.
+    Rejected { kind: ErrorKind, message: String },
+}
+
+/// What went wrong, without the details; sent along in `Error` frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Io,
+    Decode,
+    Protocol,
+    InvalidInput,
+    NotFound,
+    OutOfRange,
+    Conflict,
+    VerificationFailed,
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Io(_) => ErrorKind::Io,
+            Error::Decode(_) => ErrorKind::Decode,
+            Error::Protocol(_) => ErrorKind::Protocol,
+            Error::InvalidInput(_) => ErrorKind::InvalidInput,
+            Error::NotFound(_) => ErrorKind::NotFound,
+            Error::OutOfRange(_) => ErrorKind::OutOfRange,
+            Error::Conflict(_) => ErrorKind::Conflict,
+            Error::VerificationFailed(_) => ErrorKind::VerificationFailed,
+            Error::Rejected { kind, .. } => *kind,
+        }
+    }
+
+    /// The error without its kind, as sent to the peer.
+    pub fn message(&self) -> String {
+        match self {
+            Error::Io(e) => e.to_string(),
+            Error::Decode(message)
+            | Error::Protocol(message)
+            | Error::InvalidInput(message)
+            | Error::NotFound(message)
+            | Error::OutOfRange(message)
+            | Error::Conflict(message)
+            | Error::VerificationFailed(message)
+            | Error::Rejected { message, .. } => message.clone(),
+        }
+    }
+}
+
+impl ErrorKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::Io => "I/O error",
+            ErrorKind::Decode => "malformed message",
+            ErrorKind::Protocol => "protocol violation",
+            ErrorKind::InvalidInput => "invalid input",
+            ErrorKind::NotFound => "not found",
+            ErrorKind::OutOfRange => "out of range",
+            ErrorKind::Conflict => "conflict",
+            ErrorKind::VerificationFailed => "verification failed",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Decode(message) => write!(f, "malformed message: {}", message),
+            Error::Protocol(message) => write!(f, "protocol violation: {}", message),
+            Error::Rejected { kind, message } => write!(f, "peer reported an error ({}): {}", kind.name(), message),
+            other => f.write_str(&other.message()),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error)
+    }
+}
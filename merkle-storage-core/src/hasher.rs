@@ -0,0 +1,381 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::str::FromStr;
+
+use rs_merkle::Hasher;
+use serde::{Deserialize, Serialize};
+use sha2::{digest::FixedOutput, Digest, Sha256, Sha512_256};
+use sha3::{Keccak256, Sha3_256};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, Hash, PartialEq)]
+pub struct Sha256Algorithm {}
+
+impl Hasher for Sha256Algorithm {
+    type Hash = [u8; 32];
+
+    fn hash(data: &[u8]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+
+        hasher.update(data);
+        <[u8; 32]>::from(hasher.finalize_fixed())
+    }
+}
+
+/// SHA-512 truncated to 256 bits, faster than SHA-256 on 64-bit machines
+/// without SHA extensions.
+#[derive(Debug, Clone)]
+pub struct Sha512_256Algorithm {}
+
+impl Hasher for Sha512_256Algorithm {
+    type Hash = [u8; 32];
+
+    fn hash(data: &[u8]) -> [u8; 32] {
+        <[u8; 32]>::from(Sha512_256::digest(data))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Sha3_256Algorithm {}
+
+impl Hasher for Sha3_256Algorithm {
+    type Hash = [u8; 32];
+
+    fn hash(data: &[u8]) -> [u8; 32] {
+        <[u8; 32]>::from(Sha3_256::digest(data))
+    }
+}
+
+/// Keccak-256 with its original padding, as Ethereum uses it, so roots can be
+/// checked on-chain.
+#[derive(Debug, Clone)]
+pub struct Keccak256Algorithm {}
+
+impl Hasher for Keccak256Algorithm {
+    type Hash = [u8; 32];
+
+    fn hash(data: &[u8]) -> [u8; 32] {
+        <[u8; 32]>::from(Keccak256::digest(data))
+    }
+}
+
+/// BLAKE3, by far the fastest of the lot for bulk data.
+#[derive(Debug, Clone)]
+pub struct Blake3Algorithm {}
+
+impl Hasher for Blake3Algorithm {
+    type Hash = [u8; 32];
+
+    fn hash(data: &[u8]) -> [u8; 32] {
+        *blake3::hash(data).as_bytes()
+    }
+}
+
+/// Hashers the tree of an object can be built with.
+pub trait MerkleHasher: Hasher<Hash = [u8; 32]> + 'static {
+    /// Hash of a chunk as a leaf of the tree.
+    fn hash_leaf(data: &[u8]) -> [u8; 32] {
+        Self::hash(data)
+    }
+}
+
+impl MerkleHasher for Sha256Algorithm {}
+impl MerkleHasher for Sha512_256Algorithm {}
+impl MerkleHasher for Sha3_256Algorithm {}
+impl MerkleHasher for Keccak256Algorithm {}
+impl MerkleHasher for Blake3Algorithm {}
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+/// RFC 6962 domain separation on top of another hasher: leaves are hashed
+/// as H(0x00 || data) and interior nodes as H(0x01 || left || right), so a
+/// chunk can never hash to the same value as an interior node, whatever its
+/// content. An odd node is still carried up unchanged.
+#[derive(Debug)]
+pub struct DomainSeparated<H>(PhantomData<H>);
+
+impl<H> Clone for DomainSeparated<H> {
+    fn clone(&self) -> Self {
+        DomainSeparated(PhantomData)
+    }
+}
+
+impl<H: Hasher<Hash = [u8; 32]>> Hasher for DomainSeparated<H> {
+    type Hash = [u8; 32];
+
+    fn hash(data: &[u8]) -> [u8; 32] {
+        H::hash(data)
+    }
+
+    fn concat_and_hash(left: &[u8; 32], right: Option<&[u8; 32]>) -> [u8; 32] {
+        match right {
+            Some(right) => {
+                let mut node = Vec::with_capacity(1 + 2 * 32);
+                node.push(NODE_PREFIX);
+                node.extend_from_slice(left);
+                node.extend_from_slice(right);
+                H::hash(&node)
+            }
+            None => *left,
+        }
+    }
+}
+
+impl<H: Hasher<Hash = [u8; 32]> + 'static> MerkleHasher for DomainSeparated<H> {
+    fn hash_leaf(data: &[u8]) -> [u8; 32] {
+        let mut leaf = Vec::with_capacity(1 + data.len());
+        leaf.push(LEAF_PREFIX);
+        leaf.extend_from_slice(data);
+        H::hash(&leaf)
+    }
+}
+
+/// Hash function underneath an object's tree.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    #[serde(rename = "sha512-256")]
+    Sha512_256,
+    #[serde(rename = "sha3-256")]
+    Sha3_256,
+    Keccak256,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub const ALL: [HashAlgorithm; 5] = [
+        HashAlgorithm::Sha256,
+        HashAlgorithm::Sha512_256,
+        HashAlgorithm::Sha3_256,
+        HashAlgorithm::Keccak256,
+        HashAlgorithm::Blake3,
+    ];
+
+    /// Name under which objects record the algorithm their tree was built with.
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512_256 => "sha512-256",
+            HashAlgorithm::Sha3_256 => "sha3-256",
+            HashAlgorithm::Keccak256 => "keccak256",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<HashAlgorithm, String> {
+        HashAlgorithm::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.name() == name)
+            .ok_or_else(|| {
+                let names: Vec<&str> = HashAlgorithm::ALL.iter().map(HashAlgorithm::name).collect();
+                format!("unknown hash algorithm {}, expected one of {}", name, names.join(", "))
+            })
+    }
+}
+
+/// How the tree of an object is hashed. Both peers need it to derive the same
+/// tree, so it is chosen at upload and kept in the object's metadata, where
+/// it is flattened into `hasher` and `domain_separated` fields; objects
+/// recorded before either existed read back as plain SHA-256.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TreeHasher {
+    #[serde(rename = "hasher", default)]
+    pub algorithm: HashAlgorithm,
+    /// Whether leaves and interior nodes are hashed in separate domains, see
+    /// [`DomainSeparated`]. Off for objects stored before it existed.
+    #[serde(default)]
+    pub domain_separated: bool,
+}
+
+impl TreeHasher {
+    /// What new objects are built with.
+    pub fn new(algorithm: HashAlgorithm) -> TreeHasher {
+        TreeHasher { algorithm, domain_separated: true }
+    }
+}
+
+impl fmt::Display for TreeHasher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.algorithm)?;
+        if self.domain_separated {
+            f.write_str("+rfc6962")?;
+        }
+        Ok(())
+    }
+}
+
+/// Evaluates `$body` with `$H` bound to the [`MerkleHasher`] type a
+/// [`TreeHasher`] describes.
+macro_rules! with_tree_hasher {
+    ($tree_hasher:expr, $H:ident => $body:expr) => {{
+        use $crate::hasher::{
+            Blake3Algorithm, HashAlgorithm, Keccak256Algorithm, Sha256Algorithm, Sha3_256Algorithm,
+            Sha512_256Algorithm,
+        };
+        let tree_hasher: $crate::hasher::TreeHasher = $tree_hasher;
+        let domain_separated = tree_hasher.domain_separated;
+        match tree_hasher.algorithm {
+            HashAlgorithm::Sha256 => $crate::hasher::with_domain!(domain_separated, Sha256Algorithm, $H => $body),
+            HashAlgorithm::Sha512_256 => $crate::hasher::with_domain!(domain_separated, Sha512_256Algorithm, $H => $body),
+            HashAlgorithm::Sha3_256 => $crate::hasher::with_domain!(domain_separated, Sha3_256Algorithm, $H => $body),
+            HashAlgorithm::Keccak256 => $crate::hasher::with_domain!(domain_separated, Keccak256Algorithm, $H => $body),
+            HashAlgorithm::Blake3 => $crate::hasher::with_domain!(domain_separated, Blake3Algorithm, $H => $body),
+        }
+    }};
+}
+pub(crate) use with_tree_hasher;
+
+/// Binds `$H` to the hasher `$A`, wrapped in [`DomainSeparated`] if asked.
+macro_rules! with_domain {
+    ($domain_separated:expr, $A:ty, $H:ident => $body:expr) => {
+        if $domain_separated {
+            type $H = $crate::hasher::DomainSeparated<$A>;
+            $body
+        } else {
+            type $H = $A;
+            $body
+        }
+    };
+}
+pub(crate) use with_domain;
+
+/// Binds a challenged chunk to the nonce of one audit: H(nonce || chunk).
+/// A server can only produce it with the chunk at hand when the challenge
+/// arrives, so answers cannot be precomputed.
+pub fn nonce_tag(nonce: &[u8; 32], chunk: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+
+    hasher.update(nonce);
+    hasher.update(chunk);
+    <[u8; 32]>::from(hasher.finalize_fixed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha256(parts: &[&[u8]]) -> [u8; 32] {
+        Sha256Algorithm::hash(&parts.concat())
+    }
+
+    #[test]
+    fn leaves_and_nodes_are_prefixed() {
+        type H = DomainSeparated<Sha256Algorithm>;
+        let (left, right) = ([1u8; 32], [2u8; 32]);
+        assert_eq!(H::hash_leaf(b"chunk"), sha256(&[&[0x00], b"chunk"]));
+        assert_eq!(H::concat_and_hash(&left, Some(&right)), sha256(&[&[0x01], &left, &right]));
+        assert_eq!(H::concat_and_hash(&left, None), left);
+    }
+
+    #[test]
+    fn empty_leaf_matches_rfc_6962() {
+        let expected = hex::decode("6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d").unwrap();
+        assert_eq!(DomainSeparated::<Sha256Algorithm>::hash_leaf(b"").to_vec(), expected);
+    }
+
+    /// Published digests of the empty input and of "abc" for each algorithm.
+    const KNOWN_ANSWERS: [(HashAlgorithm, &str, &str); 5] = [
+        (
+            HashAlgorithm::Sha256,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+        ),
+        (
+            HashAlgorithm::Sha512_256,
+            "c672b8d1ef56ed28ab87c3622c5114069bdd3ad7b8f9737498d0c01ecef0967a",
+            "53048e2681941ef99b2e29b76b4c7dabe4c2d0c634fc6d46e0e2f13107e7af23",
+        ),
+        (
+            HashAlgorithm::Sha3_256,
+            "a7ffc6f8bf1ed76651c14756a061d662f580ff4de43b49fa82d80a4b80f8434a",
+            "3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532",
+        ),
+        (
+            HashAlgorithm::Keccak256,
+            "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470",
+            "4e03657aea45a94fc7d47ba826c8d667c0d1e6e33a64a036ec44f58fa12d6c45",
+        ),
+        (
+            HashAlgorithm::Blake3,
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85",
+        ),
+    ];
+
+    #[test]
+    fn hashers_match_their_standard_digests() {
+        let hashes = |data: &[u8]| {
+            [
+                Sha256Algorithm::hash(data),
+                Sha512_256Algorithm::hash(data),
+                Sha3_256Algorithm::hash(data),
+                Keccak256Algorithm::hash(data),
+                Blake3Algorithm::hash(data),
+            ]
+        };
+        let answers = hashes(b"").into_iter().zip(hashes(b"abc"));
+        for ((empty_hash, abc_hash), (algorithm, empty, abc)) in answers.zip(KNOWN_ANSWERS) {
+            assert_eq!(hex::encode(empty_hash), empty, "{}", algorithm);
+            assert_eq!(hex::encode(abc_hash), abc, "{}", algorithm);
+        }
+    }
+
+    #[test]
+    fn algorithms_pick_their_hasher() {
+        for (algorithm, empty, abc) in KNOWN_ANSWERS {
+            let plain = TreeHasher { algorithm, domain_separated: false };
+            with_tree_hasher!(plain, H => {
+                assert_eq!(hex::encode(H::hash_leaf(b"")), empty, "{}", algorithm);
+                assert_eq!(hex::encode(H::hash_leaf(b"abc")), abc, "{}", algorithm);
+            });
+        }
+    }
+
+    #[test]
+    fn plain_hashers_hash_leaves_unprefixed() {
+        assert_eq!(Sha256Algorithm::hash_leaf(b"chunk"), sha256(&[b"chunk"]));
+    }
+
+    #[test]
+    fn a_chunk_cannot_pass_for_an_interior_node() {
+        let (left, right) = ([3u8; 32], [4u8; 32]);
+        let forged_chunk = [left, right].concat();
+
+        // without prefixes the chunk and the node hash alike
+        assert_eq!(Sha256Algorithm::hash_leaf(&forged_chunk), Sha256Algorithm::concat_and_hash(&left, Some(&right)));
+        for algorithm in HashAlgorithm::ALL {
+            with_tree_hasher!(TreeHasher::new(algorithm), H => {
+                assert_ne!(H::hash_leaf(&forged_chunk), H::concat_and_hash(&left, Some(&right)), "{}", algorithm);
+            });
+        }
+    }
+
+    #[test]
+    fn domain_separation_changes_every_algorithm() {
+        for algorithm in HashAlgorithm::ALL {
+            let plain = TreeHasher { algorithm, domain_separated: false };
+            let separated = with_tree_hasher!(TreeHasher::new(algorithm), H => H::hash_leaf(b"chunk"));
+            assert_ne!(with_tree_hasher!(plain, H => H::hash_leaf(b"chunk")), separated, "{}", algorithm);
+        }
+    }
+
+    #[test]
+    fn algorithm_names_round_trip() {
+        for algorithm in HashAlgorithm::ALL {
+            assert_eq!(algorithm.name().parse::<HashAlgorithm>(), Ok(algorithm));
+        }
+        assert!("md5".parse::<HashAlgorithm>().is_err());
+    }
+}
@@ -0,0 +1,220 @@
+use std::fmt;
+use std::ops::Range;
+
+use fastcdc::v2020::{
+    FastCDC, AVERAGE_MAX, AVERAGE_MIN, MAXIMUM_MAX, MAXIMUM_MIN, MINIMUM_MAX, MINIMUM_MIN,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::hasher::{with_tree_hasher, MerkleHasher, TreeHasher};
+
+pub const DEFAULT_CHUNK_SIZE: usize = 4096;
+
+/// How an object's data is cut into the leaf values of its tree. Both peers
+/// must use the same chunking to derive the same tree, so it is part of an
+/// object's metadata.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "kebab-case")]
+pub enum Chunking {
+    /// Consecutive chunks of `size` bytes, the last one possibly shorter.
+    Fixed { size: usize },
+    /// FastCDC chunks whose boundaries follow the content, so an insertion
+    /// near the start of a file only changes the chunks around it.
+    ContentDefined { min: usize, avg: usize, max: usize },
+}
+
+impl Chunking {
+    /// Content-defined chunking around an average size, with the customary
+    /// FastCDC bounds of a quarter and four times the average. A maximum
+    /// that would overflow saturates, for [`Chunking::validate`] to refuse.
+    pub fn content_defined(avg: usize) -> Chunking {
+        Chunking::ContentDefined { min: avg / 4, avg, max: avg.saturating_mul(4) }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        match *self {
+            Chunking::Fixed { size: 0 } => Err(Error::InvalidInput("chunk size must be positive".to_string())),
+            Chunking::Fixed { size } if u32::try_from(size).is_err() => {
+                Err(Error::InvalidInput(format!("chunk size {} does not fit in 32 bits", size)))
+            }
+            Chunking::Fixed { .. } => Ok(()),
+            Chunking::ContentDefined { min, avg, max } => {
+                check_range("minimum", min, MINIMUM_MIN, MINIMUM_MAX)?;
+                check_range("average", avg, AVERAGE_MIN, AVERAGE_MAX)?;
+                check_range("maximum", max, MAXIMUM_MIN, MAXIMUM_MAX)?;
+                if min <= avg && avg <= max {
+                    Ok(())
+                } else {
+                    Err(Error::InvalidInput(
+                        "content-defined chunk sizes must satisfy min <= avg <= max".to_string(),
+                    ))
+                }
+            }
+        }
+    }
+}
+
+impl Chunking {
+    /// Largest chunk this chunking cuts.
+    pub fn max_chunk_len(&self) -> usize {
+        match *self {
+            Chunking::Fixed { size } => size,
+            Chunking::ContentDefined { max, .. } => max,
+        }
+    }
+
+    /// Checks that a chunk of `len` bytes, added to an object on its own,
+    /// could have come out of this chunking: fixed-size chunks are full
+    /// except for the object's `last` one, content-defined ones at most the
+    /// maximum.
+    pub fn check_chunk_len(&self, len: usize, last: bool) -> Result<()> {
+        let fits = match *self {
+            Chunking::Fixed { size } if last => (1..=size).contains(&len),
+            Chunking::Fixed { size } => len == size,
+            Chunking::ContentDefined { max, .. } => (1..=max).contains(&len),
+        };
+        if fits {
+            Ok(())
+        } else {
+            Err(Error::InvalidInput(format!("a chunk of {} bytes does not fit {} chunking", len, self)))
+        }
+    }
+}
+
+fn check_range(what: &str, value: usize, low: u32, high: u32) -> Result<()> {
+    if (low as usize..=high as usize).contains(&value) {
+        Ok(())
+    } else {
+        Err(Error::InvalidInput(format!(
+            "{} chunk size {} is outside {}..={}",
+            what, value, low, high
+        )))
+    }
+}
+
+impl Default for Chunking {
+    fn default() -> Self {
+        Chunking::Fixed { size: DEFAULT_CHUNK_SIZE }
+    }
+}
+
+impl fmt::Display for Chunking {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Chunking::Fixed { size } => write!(f, "fixed {} B", size),
+            Chunking::ContentDefined { min, avg, max } => {
+                write!(f, "content-defined {}/{}/{} B", min, avg, max)
+            }
+        }
+    }
+}
+
+/// Splits the data into the leaf values of the tree. `chunking` must have
+/// passed [`Chunking::validate`].
+pub fn compute_leaves<'a>(data: &'a [u8], chunking: &Chunking) -> Vec<&'a [u8]> {
+    match *chunking {
+        Chunking::Fixed { size } => data.chunks(size).collect(),
+        Chunking::ContentDefined { min, avg, max } => {
+            FastCDC::new(data, min as u32, avg as u32, max as u32)
+                .map(|chunk| &data[chunk.offset..chunk.offset + chunk.length])
+                .collect()
+        }
+    }
+}
+
+/// Indices of the fixed-size chunks of `chunk_size` bytes that cover
+/// `length` bytes from `offset`, or `None` for an empty range or one that
+/// runs past the addressable bytes.
+pub fn covering_chunks(chunk_size: usize, offset: usize, length: usize) -> Option<Range<usize>> {
+    let end = offset.checked_add(length).filter(|_| length > 0)?;
+    Some(offset / chunk_size..end.div_ceil(chunk_size))
+}
+
+pub fn hash_leaf(tree_hasher: TreeHasher, leaf: &[u8]) -> [u8; 32] {
+    with_tree_hasher!(tree_hasher, H => H::hash_leaf(leaf))
+}
+
+pub fn hash_leaves(tree_hasher: TreeHasher, leaves: &[&[u8]]) -> Vec<[u8; 32]> {
+    with_tree_hasher!(tree_hasher, H => leaves.iter().map(|x| H::hash_leaf(x)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reproducible data without patterns FastCDC could latch on to.
+    fn pseudo_random(len: usize, mut state: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn content_defined_chunks_are_deterministic_and_bounded() {
+        let data = pseudo_random(1 << 20, 1);
+        let chunking = Chunking::content_defined(4096);
+        chunking.validate().unwrap();
+        let chunks = compute_leaves(&data, &chunking);
+        assert_eq!(chunks, compute_leaves(&data, &chunking));
+        assert_eq!(chunks.concat(), data);
+        let (last, rest) = chunks.split_last().unwrap();
+        assert!(rest.iter().all(|chunk| (1024..=16384).contains(&chunk.len())));
+        assert!((1..=16384).contains(&last.len()));
+        for (i, chunk) in chunks.iter().enumerate() {
+            chunking.check_chunk_len(chunk.len(), i == chunks.len() - 1).unwrap();
+        }
+    }
+
+    #[test]
+    fn an_insertion_only_changes_nearby_chunks() {
+        let data = pseudo_random(1 << 20, 2);
+        let mut edited = data[..1000].to_vec();
+        edited.extend_from_slice(b"a few inserted bytes");
+        edited.extend_from_slice(&data[1000..]);
+
+        let chunking = Chunking::content_defined(4096);
+        let before = compute_leaves(&data, &chunking);
+        let after = compute_leaves(&edited, &chunking);
+        let unchanged = after.iter().filter(|chunk| before.contains(chunk)).count();
+        assert!(unchanged + 3 >= before.len(), "{} of {} chunks survived", unchanged, before.len());
+    }
+
+    #[test]
+    fn fixed_chunks_are_full_but_the_last() {
+        let data = pseudo_random(10_000, 3);
+        let chunks = compute_leaves(&data, &Chunking::Fixed { size: 4096 });
+        let lengths: Vec<usize> = chunks.iter().map(|chunk| chunk.len()).collect();
+        assert_eq!(lengths, [4096, 4096, 1808]);
+        let chunking = Chunking::Fixed { size: 4096 };
+        assert!(chunking.check_chunk_len(1808, true).is_ok());
+        assert!(chunking.check_chunk_len(1808, false).is_err());
+        assert!(chunking.check_chunk_len(4097, true).is_err());
+    }
+
+    #[test]
+    fn out_of_range_chunkings_are_refused() {
+        let invalid = |chunking: Chunking| matches!(chunking.validate(), Err(Error::InvalidInput(_)));
+        assert!(invalid(Chunking::Fixed { size: 0 }));
+        assert!(invalid(Chunking::content_defined(16)));
+        assert!(invalid(Chunking::content_defined(16 << 20)));
+        assert!(invalid(Chunking::content_defined(usize::MAX)));
+        assert!(invalid(Chunking::ContentDefined { min: 8192, avg: 4096, max: 16384 }));
+        assert!(Chunking::content_defined(DEFAULT_CHUNK_SIZE).validate().is_ok());
+        assert_eq!(Chunking::content_defined(8192).max_chunk_len(), 32768);
+    }
+
+    #[test]
+    fn covering_chunks_span_the_range() {
+        assert_eq!(covering_chunks(4096, 0, 1), Some(0..1));
+        assert_eq!(covering_chunks(4096, 4095, 2), Some(0..2));
+        assert_eq!(covering_chunks(4096, 8192, 4096), Some(2..3));
+        assert_eq!(covering_chunks(4096, 10, 0), None);
+        assert_eq!(covering_chunks(4096, usize::MAX, 1), None);
+    }
+}
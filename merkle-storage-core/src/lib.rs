@@ -0,0 +1,25 @@
+//! Shared building blocks of the merkle-tree-storage peers: the hasher, leaf
+//! splitting, tree building, proof generation/verification and the wire codec.
+
+pub mod codec;
+pub mod error;
+pub mod hasher;
+pub mod leaves;
+pub mod limits;
+pub mod object;
+pub mod protocol;
+pub mod sampling;
+pub mod tree;
+
+pub use error::{Error, ErrorKind, Result};
+pub use hasher::{
+    nonce_tag, Blake3Algorithm, DomainSeparated, HashAlgorithm, Keccak256Algorithm, MerkleHasher, Sha256Algorithm,
+    Sha3_256Algorithm, Sha512_256Algorithm, TreeHasher,
+};
+pub use leaves::{compute_leaves, covering_chunks, hash_leaf, hash_leaves, Chunking, DEFAULT_CHUNK_SIZE};
+pub use limits::Limits;
+pub use object::ObjectId;
+pub use tree::{
+    compute_appended_root, compute_consistency_proof, compute_merkle_proof, compute_merkle_tree, compute_root,
+    verify_consistency_proof, verify_merkle_proof, ObjectTree,
+};
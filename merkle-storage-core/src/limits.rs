@@ -0,0 +1,146 @@
+//! Bounds a peer puts on what it accepts from the other side. Frame headers,
+//! counts and indices all come off the wire, so each is checked against
+//! these before anything is allocated for it.
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+/// Bytes an answer carrying chunks takes besides its chunks, proof and tags:
+/// the message tag, the tree hasher, the first index of a read and the count
+/// in front of each list, with room to spare.
+const ANSWER_OVERHEAD: usize = 64;
+
+/// Bytes a `Store` frame takes besides the data: the message tag, the
+/// chunking at its longest, the tree hasher and the length of the data.
+pub(crate) const STORE_OVERHEAD: usize = 1 + 13 + 2 + 4;
+
+/// Per-peer limits. Both sides should agree on them: a frame one side sends
+/// is refused by the other if it exceeds the receiver's limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Limits {
+    /// Largest `Store` frame; the whole upload travels in it.
+    pub max_store_frame: usize,
+    /// Largest frame of the other messages carrying chunks: appends,
+    /// replacements, challenge, download and read answers.
+    pub max_chunk_frame: usize,
+    /// Largest frame of any other message, which carry only ids, hashes and
+    /// counts.
+    pub max_control_frame: usize,
+    /// Most leaves an object may have, and most chunks or hashes one message
+    /// may carry.
+    pub max_leaves: usize,
+    /// Most leaves one challenge may ask for.
+    pub max_challenge: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_store_frame: 256 << 20,
+            max_chunk_frame: 64 << 20,
+            max_control_frame: 1 << 20,
+            max_leaves: 1 << 24,
+            max_challenge: 4096,
+        }
+    }
+}
+
+impl Limits {
+    /// Fails if a count of `what` read off the wire exceeds `max`.
+    pub fn check_count(what: &str, count: usize, max: usize) -> Result<()> {
+        if count <= max {
+            Ok(())
+        } else {
+            Err(Error::OutOfRange(format!("{} {} exceed the limit of {}", count, what, max)))
+        }
+    }
+
+    pub fn check_leaves(&self, count: usize) -> Result<()> {
+        Limits::check_count("leaves", count, self.max_leaves)
+    }
+
+    pub fn check_challenge(&self, count: usize) -> Result<()> {
+        Limits::check_count("challenged leaves", count, self.max_challenge)
+    }
+
+    /// Fails if an upload of `len` bytes would not fit a `Store` frame.
+    pub fn check_store(&self, len: usize) -> Result<()> {
+        if len <= self.max_store_frame.saturating_sub(STORE_OVERHEAD) {
+            return Ok(());
+        }
+        Err(Error::OutOfRange(format!(
+            "an upload of {} bytes does not fit a Store frame of {} bytes",
+            len, self.max_store_frame
+        )))
+    }
+
+    /// Most leaves of an object with `leaf_count` leaves and chunks of up to
+    /// `max_chunk_len` bytes one challenge may ask for, so that the answer
+    /// fits a chunk frame: every challenged leaf brings its chunk, a nonce
+    /// tag and at most one proof hash per level of the tree. Zero if not even
+    /// one leaf fits.
+    pub fn max_challenge_for(&self, max_chunk_len: usize, leaf_count: usize) -> usize {
+        self.chunks_per_answer(max_chunk_len, leaf_count).min(self.max_challenge)
+    }
+
+    /// Most chunks of `chunk_size` bytes of an object with `leaf_count`
+    /// leaves one read of a byte range may cover, so that the chunks fit a
+    /// chunk frame with their multi-proof. Zero if not even one chunk fits.
+    pub fn max_read_for(&self, chunk_size: usize, leaf_count: usize) -> usize {
+        self.chunks_per_answer(chunk_size, leaf_count).min(self.max_leaves)
+    }
+
+    fn chunks_per_answer(&self, max_chunk_len: usize, leaf_count: usize) -> usize {
+        let depth = leaf_count.next_power_of_two().trailing_zeros() as usize;
+        let per_leaf = max_chunk_len.saturating_add(4 + 32 + 32 * depth);
+        self.max_chunk_frame.saturating_sub(ANSWER_OVERHEAD) / per_leaf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_up_to_the_limit_pass() {
+        let limits = Limits { max_leaves: 10, max_challenge: 3, ..Limits::default() };
+        assert!(limits.check_leaves(10).is_ok());
+        assert!(matches!(limits.check_leaves(11), Err(Error::OutOfRange(_))));
+        assert!(limits.check_challenge(3).is_ok());
+        assert!(matches!(limits.check_challenge(4), Err(Error::OutOfRange(_))));
+        let limits = Limits { max_store_frame: 1000, ..Limits::default() };
+        assert!(limits.check_store(1000 - STORE_OVERHEAD).is_ok());
+        assert!(matches!(limits.check_store(1001 - STORE_OVERHEAD), Err(Error::OutOfRange(_))));
+    }
+
+    #[test]
+    fn challenge_answers_fit_a_chunk_frame() {
+        let limits = Limits::default();
+        for (chunk_len, leaf_count) in [(4096, 1), (4096, 1 << 20), (4 << 20, 20), (16 << 20, 1 << 24)] {
+            let fitting = limits.max_challenge_for(chunk_len, leaf_count);
+            assert!(fitting >= 1 && fitting <= limits.max_challenge);
+            let depth = leaf_count.next_power_of_two().trailing_zeros() as usize;
+            // chunks, their length prefixes and tags, and a proof that cannot be longer
+            let answer = ANSWER_OVERHEAD + fitting * (chunk_len + 4 + 32 + 32 * depth);
+            assert!(answer <= limits.max_chunk_frame, "{} leaves of {} bytes", fitting, chunk_len);
+        }
+        // 80 MiB in 4 MiB chunks cannot be answered at once
+        assert!(limits.max_challenge_for(4 << 20, 20) < 20);
+        assert_eq!(limits.max_challenge_for(4096, 1 << 20), limits.max_challenge);
+        assert_eq!(limits.max_challenge_for(limits.max_chunk_frame, 1), 0);
+    }
+    #[test]
+    fn read_answers_fit_a_chunk_frame() {
+        let limits = Limits::default();
+        // 80 MiB in 4 MiB chunks takes several reads
+        let fitting = limits.max_read_for(4 << 20, 20);
+        assert!((1..20).contains(&fitting));
+        assert!(ANSWER_OVERHEAD + fitting * ((4 << 20) + 4 + 32 * 5) <= limits.max_chunk_frame);
+        // small chunks are bounded by the chunks one message may carry
+        let limits = Limits { max_leaves: 100, ..Limits::default() };
+        assert_eq!(limits.max_read_for(16, 1000), 100);
+        assert_eq!(limits.max_read_for(limits.max_chunk_frame, 1), 0);
+    }
+}
@@ -0,0 +1,761 @@
+//! Typed messages exchanged between client and server.
+//!
+//! Each message travels in one frame whose first byte is the message tag. A
+//! session opens with a `Hello` carrying the client's protocol version; the
+//! server answers `HelloAck` when it speaks that version and `Error`
+//! otherwise. The `Hello` layout is frozen so peers of any version can read it.
+//!
+//! Requests that read an object name the version they want by its root: the
+//! current one or any older one the server still retains. Every answer that
+//! carries a proof also declares the hasher the server built it with, so a
+//! client can tell a proof for a differently hashed tree from a bad one.
+//!
+//! Frames are read against the receiver's [`Limits`]: the length a header
+//! announces is checked against the limit for the message tag, and every
+//! count inside a message against the leaf or challenge limit, before
+//! anything is allocated for them.
+
+use std::io::{Read, Write};
+
+use crate::codec::{decode_error, read_frame, write_frame, Decoder, Encoder};
+#[cfg(feature = "tokio")]
+use crate::codec::{read_frame_async, write_frame_async};
+use crate::error::{Error, ErrorKind, Result};
+use crate::hasher::{HashAlgorithm, TreeHasher};
+use crate::leaves::Chunking;
+use crate::limits::Limits;
+use crate::object::ObjectId;
+
+pub const PROTOCOL_VERSION: u8 = 1;
+
+const TAG_HELLO: u8 = 0x01;
+const TAG_HELLO_ACK: u8 = 0x02;
+const TAG_STORE: u8 = 0x10;
+const TAG_ROOT_ACK: u8 = 0x11;
+const TAG_CHALLENGE: u8 = 0x20;
+const TAG_CHALLENGE_RESPONSE: u8 = 0x21;
+const TAG_DOWNLOAD: u8 = 0x30;
+const TAG_DOWNLOAD_CHUNK: u8 = 0x31;
+const TAG_READ_RANGE: u8 = 0x32;
+const TAG_READ_RANGE_RESPONSE: u8 = 0x33;
+const TAG_APPEND_CHUNKS: u8 = 0x40;
+const TAG_APPEND_ACK: u8 = 0x41;
+const TAG_REPLACE_CHUNK: u8 = 0x42;
+const TAG_REPLACE_ACK: u8 = 0x43;
+const TAG_PROVE_CONSISTENCY: u8 = 0x50;
+const TAG_CONSISTENCY_PROOF: u8 = 0x51;
+const TAG_LIST_VERSIONS: u8 = 0x60;
+const TAG_VERSIONS: u8 = 0x61;
+const TAG_ROLLBACK: u8 = 0x62;
+const TAG_ROLLBACK_ACK: u8 = 0x63;
+const TAG_ERROR: u8 = 0x7f;
+
+/// One retained version of an object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionInfo {
+    pub root: [u8; 32],
+    pub leaf_count: usize,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Hello { version: u8 },
+    HelloAck { version: u8 },
+    Store { chunking: Chunking, hasher: TreeHasher, data: Vec<u8> },
+    RootAck { object_id: ObjectId, root: [u8; 32] },
+    /// With a `nonce`, the response must also carry `nonce_tag(nonce, chunk)`
+    /// for every challenged chunk.
+    Challenge { object_id: ObjectId, root: [u8; 32], indices: Vec<usize>, nonce: Option<[u8; 32]> },
+    ChallengeResponse { hasher: TreeHasher, chunks: Vec<Vec<u8>>, proof: Vec<[u8; 32]>, tags: Vec<[u8; 32]> },
+    /// Asks for chunks `start..end`, answered by one `DownloadChunk` per chunk
+    /// in order.
+    Download { object_id: ObjectId, root: [u8; 32], start: usize, end: usize },
+    DownloadChunk { hasher: TreeHasher, index: usize, data: Vec<u8>, proof: Vec<[u8; 32]> },
+    /// Asks for `length` bytes from `offset` of an object with fixed-size
+    /// chunks, answered by the chunks covering them, starting at chunk
+    /// `first_index`, and one multi-proof for all of them.
+    ReadRange { object_id: ObjectId, root: [u8; 32], offset: usize, length: usize },
+    ReadRangeResponse { hasher: TreeHasher, first_index: usize, chunks: Vec<Vec<u8>>, proof: Vec<[u8; 32]> },
+    /// Appends chunks to an object whose current root is `root`. The answer
+    /// carries the old last leaf with its proof in the old tree, from which
+    /// the client derives the new root itself.
+    AppendChunks { object_id: ObjectId, root: [u8; 32], chunks: Vec<Vec<u8>> },
+    AppendAck { hasher: TreeHasher, last_leaf: [u8; 32], proof: Vec<[u8; 32]>, root: [u8; 32] },
+    /// Replaces chunk `index` of an object whose current root is `root`. The
+    /// answer carries the old chunk with its proof in the old tree.
+    ReplaceChunk { object_id: ObjectId, root: [u8; 32], index: usize, data: Vec<u8> },
+    ReplaceAck { hasher: TreeHasher, old_chunk: Vec<u8>, proof: Vec<[u8; 32]>, root: [u8; 32] },
+    /// Asks for the current root of an object and a proof that its tree
+    /// extends the tree of its first `old_leaf_count` leaves.
+    ProveConsistency { object_id: ObjectId, old_leaf_count: usize },
+    ConsistencyProof { hasher: TreeHasher, leaf_count: usize, root: [u8; 32], proof: Vec<[u8; 32]> },
+    ListVersions { object_id: ObjectId },
+    /// Retained versions, oldest first, ending with the current one.
+    Versions { versions: Vec<VersionInfo> },
+    /// Makes the retained version with root `target` current again, if the
+    /// current root is `root`.
+    Rollback { object_id: ObjectId, root: [u8; 32], target: [u8; 32] },
+    RollbackAck { root: [u8; 32], leaf_count: usize },
+    Error { kind: ErrorKind, message: String },
+}
+
+impl Message {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::Hello { .. } => "Hello",
+            Message::HelloAck { .. } => "HelloAck",
+            Message::Store { .. } => "Store",
+            Message::RootAck { .. } => "RootAck",
+            Message::Challenge { .. } => "Challenge",
+            Message::ChallengeResponse { .. } => "ChallengeResponse",
+            Message::Download { .. } => "Download",
+            Message::DownloadChunk { .. } => "DownloadChunk",
+            Message::ReadRange { .. } => "ReadRange",
+            Message::ReadRangeResponse { .. } => "ReadRangeResponse",
+            Message::AppendChunks { .. } => "AppendChunks",
+            Message::AppendAck { .. } => "AppendAck",
+            Message::ReplaceChunk { .. } => "ReplaceChunk",
+            Message::ReplaceAck { .. } => "ReplaceAck",
+            Message::ProveConsistency { .. } => "ProveConsistency",
+            Message::ConsistencyProof { .. } => "ConsistencyProof",
+            Message::ListVersions { .. } => "ListVersions",
+            Message::Versions { .. } => "Versions",
+            Message::Rollback { .. } => "Rollback",
+            Message::RollbackAck { .. } => "RollbackAck",
+            Message::Error { .. } => "Error",
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        match self {
+            Message::Hello { version } => {
+                encoder.put_u8(TAG_HELLO).put_u8(*version);
+            }
+            Message::HelloAck { version } => {
+                encoder.put_u8(TAG_HELLO_ACK).put_u8(*version);
+            }
+            Message::Store { chunking, hasher, data } => {
+                encoder.put_u8(TAG_STORE);
+                put_chunking(&mut encoder, chunking);
+                put_tree_hasher(&mut encoder, hasher);
+                encoder.put_bytes(data);
+            }
+            Message::RootAck { object_id, root } => {
+                encoder.put_u8(TAG_ROOT_ACK).put_object_id(object_id).put_hash(root);
+            }
+            Message::Challenge { object_id, root, indices, nonce } => {
+                encoder
+                    .put_u8(TAG_CHALLENGE)
+                    .put_object_id(object_id)
+                    .put_hash(root)
+                    .put_u32(indices.len() as u32);
+                for index in indices {
+                    encoder.put_u64(*index as u64);
+                }
+                match nonce {
+                    Some(nonce) => encoder.put_u8(1).put_hash(nonce),
+                    None => encoder.put_u8(0),
+                };
+            }
+            Message::ChallengeResponse { hasher, chunks, proof, tags } => {
+                encoder.put_u8(TAG_CHALLENGE_RESPONSE);
+                put_tree_hasher(&mut encoder, hasher);
+                put_chunks(&mut encoder, chunks);
+                put_hashes(&mut encoder, proof);
+                put_hashes(&mut encoder, tags);
+            }
+            Message::Download { object_id, root, start, end } => {
+                encoder
+                    .put_u8(TAG_DOWNLOAD)
+                    .put_object_id(object_id)
+                    .put_hash(root)
+                    .put_u64(*start as u64)
+                    .put_u64(*end as u64);
+            }
+            Message::DownloadChunk { hasher, index, data, proof } => {
+                encoder.put_u8(TAG_DOWNLOAD_CHUNK);
+                put_tree_hasher(&mut encoder, hasher);
+                encoder.put_u64(*index as u64).put_bytes(data);
+                put_hashes(&mut encoder, proof);
+            }
+            Message::ReadRange { object_id, root, offset, length } => {
+                encoder
+                    .put_u8(TAG_READ_RANGE)
+                    .put_object_id(object_id)
+                    .put_hash(root)
+                    .put_u64(*offset as u64)
+                    .put_u64(*length as u64);
+            }
+            Message::ReadRangeResponse { hasher, first_index, chunks, proof } => {
+                encoder.put_u8(TAG_READ_RANGE_RESPONSE);
+                put_tree_hasher(&mut encoder, hasher);
+                encoder.put_u64(*first_index as u64);
+                put_chunks(&mut encoder, chunks);
+                put_hashes(&mut encoder, proof);
+            }
+            Message::AppendChunks { object_id, root, chunks } => {
+                encoder.put_u8(TAG_APPEND_CHUNKS).put_object_id(object_id).put_hash(root);
+                put_chunks(&mut encoder, chunks);
+            }
+            Message::AppendAck { hasher, last_leaf, proof, root } => {
+                encoder.put_u8(TAG_APPEND_ACK);
+                put_tree_hasher(&mut encoder, hasher);
+                encoder.put_hash(last_leaf);
+                put_hashes(&mut encoder, proof);
+                encoder.put_hash(root);
+            }
+            Message::ReplaceChunk { object_id, root, index, data } => {
+                encoder
+                    .put_u8(TAG_REPLACE_CHUNK)
+                    .put_object_id(object_id)
+                    .put_hash(root)
+                    .put_u64(*index as u64)
+                    .put_bytes(data);
+            }
+            Message::ReplaceAck { hasher, old_chunk, proof, root } => {
+                encoder.put_u8(TAG_REPLACE_ACK);
+                put_tree_hasher(&mut encoder, hasher);
+                encoder.put_bytes(old_chunk);
+                put_hashes(&mut encoder, proof);
+                encoder.put_hash(root);
+            }
+            Message::ProveConsistency { object_id, old_leaf_count } => {
+                encoder
+                    .put_u8(TAG_PROVE_CONSISTENCY)
+                    .put_object_id(object_id)
+                    .put_u64(*old_leaf_count as u64);
+            }
+            Message::ConsistencyProof { hasher, leaf_count, root, proof } => {
+                encoder.put_u8(TAG_CONSISTENCY_PROOF);
+                put_tree_hasher(&mut encoder, hasher);
+                encoder.put_u64(*leaf_count as u64).put_hash(root);
+                put_hashes(&mut encoder, proof);
+            }
+            Message::ListVersions { object_id } => {
+                encoder.put_u8(TAG_LIST_VERSIONS).put_object_id(object_id);
+            }
+            Message::Versions { versions } => {
+                encoder.put_u8(TAG_VERSIONS).put_u32(versions.len() as u32);
+                for version in versions {
+                    encoder
+                        .put_hash(&version.root)
+                        .put_u64(version.leaf_count as u64)
+                        .put_u64(version.created_at);
+                }
+            }
+            Message::Rollback { object_id, root, target } => {
+                encoder
+                    .put_u8(TAG_ROLLBACK)
+                    .put_object_id(object_id)
+                    .put_hash(root)
+                    .put_hash(target);
+            }
+            Message::RollbackAck { root, leaf_count } => {
+                encoder
+                    .put_u8(TAG_ROLLBACK_ACK)
+                    .put_hash(root)
+                    .put_u64(*leaf_count as u64);
+            }
+            Message::Error { kind, message } => {
+                encoder.put_u8(TAG_ERROR).put_u8(error_code(*kind)).put_str(message);
+            }
+        }
+        encoder.finish()
+    }
+
+    pub fn decode(frame: &[u8], limits: &Limits) -> Result<Message> {
+        let mut decoder = Decoder::new(frame);
+        let message = match decoder.get_u8()? {
+            TAG_HELLO => Message::Hello { version: decoder.get_u8()? },
+            TAG_HELLO_ACK => Message::HelloAck { version: decoder.get_u8()? },
+            TAG_STORE => Message::Store {
+                chunking: get_chunking(&mut decoder)?,
+                hasher: get_tree_hasher(&mut decoder)?,
+                data: decoder.get_bytes()?.to_vec(),
+            },
+            TAG_ROOT_ACK => Message::RootAck {
+                object_id: decoder.get_object_id()?,
+                root: decoder.get_hash()?,
+            },
+            TAG_CHALLENGE => {
+                let object_id = decoder.get_object_id()?;
+                let root = decoder.get_hash()?;
+                let count = decoder.get_u32()? as usize;
+                limits.check_challenge(count)?;
+                let indices = (0..count)
+                    .map(|_| get_index(&mut decoder))
+                    .collect::<Result<_>>()?;
+                let nonce = match decoder.get_u8()? {
+                    0 => None,
+                    1 => Some(decoder.get_hash()?),
+                    flag => return Err(decode_error(format!("invalid nonce flag {}", flag))),
+                };
+                Message::Challenge { object_id, root, indices, nonce }
+            }
+            TAG_CHALLENGE_RESPONSE => Message::ChallengeResponse {
+                hasher: get_tree_hasher(&mut decoder)?,
+                chunks: get_chunks(&mut decoder, limits)?,
+                proof: get_hashes(&mut decoder, limits)?,
+                tags: get_hashes(&mut decoder, limits)?,
+            },
+            TAG_DOWNLOAD => Message::Download {
+                object_id: decoder.get_object_id()?,
+                root: decoder.get_hash()?,
+                start: get_index(&mut decoder)?,
+                end: get_index(&mut decoder)?,
+            },
+            TAG_DOWNLOAD_CHUNK => Message::DownloadChunk {
+                hasher: get_tree_hasher(&mut decoder)?,
+                index: get_index(&mut decoder)?,
+                data: decoder.get_bytes()?.to_vec(),
+                proof: get_hashes(&mut decoder, limits)?,
+            },
+            TAG_READ_RANGE => Message::ReadRange {
+                object_id: decoder.get_object_id()?,
+                root: decoder.get_hash()?,
+                offset: get_index(&mut decoder)?,
+                length: get_index(&mut decoder)?,
+            },
+            TAG_READ_RANGE_RESPONSE => Message::ReadRangeResponse {
+                hasher: get_tree_hasher(&mut decoder)?,
+                first_index: get_index(&mut decoder)?,
+                chunks: get_chunks(&mut decoder, limits)?,
+                proof: get_hashes(&mut decoder, limits)?,
+            },
+            TAG_APPEND_CHUNKS => Message::AppendChunks {
+                object_id: decoder.get_object_id()?,
+                root: decoder.get_hash()?,
+                chunks: get_chunks(&mut decoder, limits)?,
+            },
+            TAG_APPEND_ACK => Message::AppendAck {
+                hasher: get_tree_hasher(&mut decoder)?,
+                last_leaf: decoder.get_hash()?,
+                proof: get_hashes(&mut decoder, limits)?,
+                root: decoder.get_hash()?,
+            },
+            TAG_REPLACE_CHUNK => Message::ReplaceChunk {
+                object_id: decoder.get_object_id()?,
+                root: decoder.get_hash()?,
+                index: get_index(&mut decoder)?,
+                data: decoder.get_bytes()?.to_vec(),
+            },
+            TAG_REPLACE_ACK => Message::ReplaceAck {
+                hasher: get_tree_hasher(&mut decoder)?,
+                old_chunk: decoder.get_bytes()?.to_vec(),
+                proof: get_hashes(&mut decoder, limits)?,
+                root: decoder.get_hash()?,
+            },
+            TAG_PROVE_CONSISTENCY => Message::ProveConsistency {
+                object_id: decoder.get_object_id()?,
+                old_leaf_count: get_index(&mut decoder)?,
+            },
+            TAG_CONSISTENCY_PROOF => Message::ConsistencyProof {
+                hasher: get_tree_hasher(&mut decoder)?,
+                leaf_count: get_index(&mut decoder)?,
+                root: decoder.get_hash()?,
+                proof: get_hashes(&mut decoder, limits)?,
+            },
+            TAG_LIST_VERSIONS => Message::ListVersions { object_id: decoder.get_object_id()? },
+            TAG_VERSIONS => {
+                let count = decoder.get_u32()? as usize;
+                limits.check_leaves(count)?;
+                let versions = (0..count)
+                    .map(|_| {
+                        Ok(VersionInfo {
+                            root: decoder.get_hash()?,
+                            leaf_count: get_index(&mut decoder)?,
+                            created_at: decoder.get_u64()?,
+                        })
+                    })
+                    .collect::<Result<_>>()?;
+                Message::Versions { versions }
+            }
+            TAG_ROLLBACK => Message::Rollback {
+                object_id: decoder.get_object_id()?,
+                root: decoder.get_hash()?,
+                target: decoder.get_hash()?,
+            },
+            TAG_ROLLBACK_ACK => Message::RollbackAck {
+                root: decoder.get_hash()?,
+                leaf_count: get_index(&mut decoder)?,
+            },
+            TAG_ERROR => Message::Error {
+                kind: get_error_kind(&mut decoder)?,
+                message: decoder.get_str()?,
+            },
+            tag => return Err(decode_error(format!("unknown message tag {:#04x}", tag))),
+        };
+        decoder.finish()?;
+        Ok(message)
+    }
+}
+
+const CHUNKING_FIXED: u8 = 0;
+const CHUNKING_CONTENT_DEFINED: u8 = 1;
+
+fn put_chunking(encoder: &mut Encoder, chunking: &Chunking) {
+    match *chunking {
+        Chunking::Fixed { size } => {
+            encoder.put_u8(CHUNKING_FIXED).put_u32(size as u32);
+        }
+        Chunking::ContentDefined { min, avg, max } => {
+            encoder
+                .put_u8(CHUNKING_CONTENT_DEFINED)
+                .put_u32(min as u32)
+                .put_u32(avg as u32)
+                .put_u32(max as u32);
+        }
+    }
+}
+
+fn get_chunking(decoder: &mut Decoder) -> Result<Chunking> {
+    match decoder.get_u8()? {
+        CHUNKING_FIXED => Ok(Chunking::Fixed { size: decoder.get_u32()? as usize }),
+        CHUNKING_CONTENT_DEFINED => Ok(Chunking::ContentDefined {
+            min: decoder.get_u32()? as usize,
+            avg: decoder.get_u32()? as usize,
+            max: decoder.get_u32()? as usize,
+        }),
+        mode => Err(decode_error(format!("unknown chunking mode {}", mode))),
+    }
+}
+
+const ALGORITHM_SHA256: u8 = 0;
+const ALGORITHM_SHA512_256: u8 = 1;
+const ALGORITHM_SHA3_256: u8 = 2;
+const ALGORITHM_KECCAK256: u8 = 3;
+const ALGORITHM_BLAKE3: u8 = 4;
+
+fn put_tree_hasher(encoder: &mut Encoder, hasher: &TreeHasher) {
+    let algorithm = match hasher.algorithm {
+        HashAlgorithm::Sha256 => ALGORITHM_SHA256,
+        HashAlgorithm::Sha512_256 => ALGORITHM_SHA512_256,
+        HashAlgorithm::Sha3_256 => ALGORITHM_SHA3_256,
+        HashAlgorithm::Keccak256 => ALGORITHM_KECCAK256,
+        HashAlgorithm::Blake3 => ALGORITHM_BLAKE3,
+    };
+    encoder.put_u8(algorithm).put_u8(hasher.domain_separated as u8);
+}
+
+fn get_tree_hasher(decoder: &mut Decoder) -> Result<TreeHasher> {
+    let algorithm = match decoder.get_u8()? {
+        ALGORITHM_SHA256 => HashAlgorithm::Sha256,
+        ALGORITHM_SHA512_256 => HashAlgorithm::Sha512_256,
+        ALGORITHM_SHA3_256 => HashAlgorithm::Sha3_256,
+        ALGORITHM_KECCAK256 => HashAlgorithm::Keccak256,
+        ALGORITHM_BLAKE3 => HashAlgorithm::Blake3,
+        algorithm => return Err(decode_error(format!("unknown hash algorithm {}", algorithm))),
+    };
+    let domain_separated = match decoder.get_u8()? {
+        0 => false,
+        1 => true,
+        flag => return Err(decode_error(format!("invalid domain separation flag {}", flag))),
+    };
+    Ok(TreeHasher { algorithm, domain_separated })
+}
+
+impl From<&Error> for Message {
+    fn from(error: &Error) -> Message {
+        Message::Error { kind: error.kind(), message: error.message() }
+    }
+}
+
+const ERROR_KINDS: [ErrorKind; 8] = [
+    ErrorKind::Io,
+    ErrorKind::Decode,
+    ErrorKind::Protocol,
+    ErrorKind::InvalidInput,
+    ErrorKind::NotFound,
+    ErrorKind::OutOfRange,
+    ErrorKind::Conflict,
+    ErrorKind::VerificationFailed,
+];
+
+/// Error kinds travel as their position in [`ERROR_KINDS`].
+fn error_code(kind: ErrorKind) -> u8 {
+    ERROR_KINDS.iter().position(|k| *k == kind).unwrap_or_default() as u8
+}
+
+fn get_error_kind(decoder: &mut Decoder) -> Result<ErrorKind> {
+    let code = decoder.get_u8()?;
+    ERROR_KINDS
+        .get(code as usize)
+        .copied()
+        .ok_or_else(|| decode_error(format!("unknown error kind {}", code)))
+}
+
+fn get_index(decoder: &mut Decoder) -> Result<usize> {
+    let index = decoder.get_u64()?;
+    usize::try_from(index).map_err(decode_error)
+}
+
+fn put_chunks(encoder: &mut Encoder, chunks: &[Vec<u8>]) {
+    encoder.put_u32(chunks.len() as u32);
+    for chunk in chunks {
+        encoder.put_bytes(chunk);
+    }
+}
+
+fn get_chunks(decoder: &mut Decoder, limits: &Limits) -> Result<Vec<Vec<u8>>> {
+    let count = decoder.get_u32()? as usize;
+    limits.check_leaves(count)?;
+    (0..count)
+        .map(|_| decoder.get_bytes().map(<[u8]>::to_vec))
+        .collect()
+}
+
+fn put_hashes(encoder: &mut Encoder, hashes: &[[u8; 32]]) {
+    encoder.put_u32(hashes.len() as u32);
+    for hash in hashes {
+        encoder.put_hash(hash);
+    }
+}
+
+fn get_hashes(decoder: &mut Decoder, limits: &Limits) -> Result<Vec<[u8; 32]>> {
+    let count = decoder.get_u32()? as usize;
+    limits.check_leaves(count)?;
+    (0..count).map(|_| decoder.get_hash()).collect()
+}
+
+pub fn write_message<W: Write>(stream: &mut W, message: &Message) -> Result<()> {
+    write_frame(stream, &message.encode())
+}
+
+pub fn read_message<R: Read>(stream: &mut R, limits: &Limits) -> Result<Message> {
+    let frame = read_frame(stream, |tag, len| check_frame_len(limits, tag, len))?;
+    Message::decode(&frame, limits)
+}
+
+#[cfg(feature = "tokio")]
+pub async fn write_message_async<W>(stream: &mut W, message: &Message) -> Result<()>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    write_frame_async(stream, &message.encode()).await
+}
+
+#[cfg(feature = "tokio")]
+pub async fn read_message_async<R>(stream: &mut R, limits: &Limits) -> Result<Message>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    let frame = read_frame_async(stream, |tag, len| check_frame_len(limits, tag, len)).await?;
+    Message::decode(&frame, limits)
+}
+
+fn check_frame_len(limits: &Limits, tag: u8, len: usize) -> Result<()> {
+    let max = max_frame_len(limits, tag);
+    if len <= max {
+        Ok(())
+    } else {
+        Err(Error::OutOfRange(format!(
+            "a frame of {} bytes with tag {:#04x} exceeds the limit of {} bytes",
+            len, tag, max
+        )))
+    }
+}
+
+/// Largest frame accepted for messages with tag `tag`; only messages that
+/// carry chunks may be large.
+fn max_frame_len(limits: &Limits, tag: u8) -> usize {
+    match tag {
+        TAG_STORE => limits.max_store_frame,
+        TAG_CHALLENGE_RESPONSE
+        | TAG_DOWNLOAD_CHUNK
+        | TAG_READ_RANGE_RESPONSE
+        | TAG_APPEND_CHUNKS
+        | TAG_REPLACE_CHUNK
+        | TAG_REPLACE_ACK => limits.max_chunk_frame,
+        _ => limits.max_control_frame,
+    }
+}
+
+/// Turns a message that does not fit the current step of the session into an
+/// error, surfacing the peer's own when it sent an `Error`.
+pub fn unexpected_message(expected: &str, message: Message) -> Error {
+    match message {
+        Message::Error { kind, message } => Error::Rejected { kind, message },
+        other => Error::Protocol(format!("expected {}, got {}", expected, other.name())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn every_message() -> Vec<Message> {
+        let object_id = ObjectId([7; 16]);
+        let hasher = TreeHasher::new(HashAlgorithm::Keccak256);
+        vec![
+            Message::Hello { version: PROTOCOL_VERSION },
+            Message::HelloAck { version: PROTOCOL_VERSION },
+            Message::Store { chunking: Chunking::default(), hasher, data: b"some data".to_vec() },
+            Message::Store {
+                chunking: Chunking::content_defined(8192),
+                hasher: TreeHasher::default(),
+                data: vec![0; 100],
+            },
+            Message::RootAck { object_id, root: [1; 32] },
+            Message::Challenge { object_id, root: [1; 32], indices: vec![0, 3, 9], nonce: None },
+            Message::Challenge { object_id, root: [1; 32], indices: vec![2], nonce: Some([5; 32]) },
+            Message::ChallengeResponse {
+                hasher,
+                chunks: vec![b"a".to_vec(), Vec::new()],
+                proof: vec![[2; 32], [3; 32]],
+                tags: vec![[4; 32]],
+            },
+            Message::Download { object_id, root: [1; 32], start: 1, end: 4 },
+            Message::DownloadChunk { hasher, index: 2, data: b"chunk".to_vec(), proof: vec![[2; 32]] },
+            Message::ReadRange { object_id, root: [1; 32], offset: 10, length: 20 },
+            Message::ReadRangeResponse { hasher, first_index: 1, chunks: vec![b"b".to_vec()], proof: Vec::new() },
+            Message::AppendChunks { object_id, root: [1; 32], chunks: vec![b"c".to_vec(), b"d".to_vec()] },
+            Message::AppendAck { hasher, last_leaf: [8; 32], proof: vec![[2; 32]], root: [9; 32] },
+            Message::ReplaceChunk { object_id, root: [1; 32], index: 5, data: b"e".to_vec() },
+            Message::ReplaceAck { hasher, old_chunk: b"f".to_vec(), proof: vec![[2; 32]], root: [9; 32] },
+            Message::ProveConsistency { object_id, old_leaf_count: 3 },
+            Message::ConsistencyProof { hasher, leaf_count: 5, root: [9; 32], proof: vec![[2; 32], [3; 32]] },
+            Message::ListVersions { object_id },
+            Message::Versions {
+                versions: vec![
+                    VersionInfo { root: [1; 32], leaf_count: 3, created_at: 1_700_000_000 },
+                    VersionInfo { root: [9; 32], leaf_count: 5, created_at: 1_700_000_100 },
+                ],
+            },
+            Message::Rollback { object_id, root: [9; 32], target: [1; 32] },
+            Message::RollbackAck { root: [1; 32], leaf_count: 3 },
+            Message::Error { kind: ErrorKind::Conflict, message: "moved on".to_string() },
+        ]
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        let limits = Limits::default();
+        let mut wire = Vec::new();
+        for message in every_message() {
+            write_message(&mut wire, &message).unwrap();
+        }
+        let mut stream = wire.as_slice();
+        for message in every_message() {
+            assert_eq!(read_message(&mut stream, &limits).unwrap(), message);
+        }
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn every_error_kind_round_trips() {
+        for kind in ERROR_KINDS {
+            let message = Message::Error { kind, message: kind.name().to_string() };
+            assert_eq!(Message::decode(&message.encode(), &Limits::default()).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn hello_layout_is_frozen() {
+        assert_eq!(Message::Hello { version: 1 }.encode(), [TAG_HELLO, 1]);
+    }
+
+    #[test]
+    fn malformed_frames_are_refused() {
+        let limits = Limits::default();
+        let decode = |frame: &[u8]| Message::decode(frame, &limits);
+        assert!(matches!(decode(&[0x0f]), Err(Error::Decode(_))));
+        assert!(matches!(decode(&[TAG_HELLO]), Err(Error::Decode(_))));
+        assert!(matches!(decode(&[TAG_HELLO, 1, 0]), Err(Error::Decode(_))));
+        assert!(matches!(decode(&[TAG_ERROR, 200, 0, 0, 0, 0]), Err(Error::Decode(_))));
+
+        // a chunking mode no peer knows
+        let store = Message::Store { chunking: Chunking::default(), hasher: TreeHasher::default(), data: Vec::new() };
+        let mut frame = store.encode();
+        frame[1] = 9;
+        assert!(matches!(decode(&frame), Err(Error::Decode(_))));
+    }
+
+    #[test]
+    fn peer_errors_surface_as_rejections() {
+        let error = Error::NotFound("unknown object".to_string());
+        match unexpected_message("RootAck", Message::from(&error)) {
+            Error::Rejected { kind, message } => {
+                assert_eq!(kind, ErrorKind::NotFound);
+                assert_eq!(message, "unknown object");
+            }
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+        assert!(matches!(unexpected_message("RootAck", Message::HelloAck { version: 1 }), Error::Protocol(_)));
+    }
+
+    fn small_limits() -> Limits {
+        Limits { max_store_frame: 1000, max_chunk_frame: 500, max_control_frame: 100, max_leaves: 8, max_challenge: 4 }
+    }
+
+    /// Reads a frame with the given tag and length, of which only the header
+    /// and the tag are there.
+    fn read_announced(tag: u8, len: u32) -> Result<Message> {
+        let mut wire = len.to_be_bytes().to_vec();
+        wire.push(tag);
+        read_message(&mut wire.as_slice(), &small_limits())
+    }
+
+    #[test]
+    fn frame_limits_depend_on_the_tag() {
+        let over = |result: Result<Message>| matches!(result, Err(Error::OutOfRange(_)));
+        let truncated = |result: Result<Message>| matches!(result, Err(Error::Io(_)));
+
+        assert!(truncated(read_announced(TAG_STORE, 1000)));
+        assert!(over(read_announced(TAG_STORE, 1001)));
+        for tag in [
+            TAG_CHALLENGE_RESPONSE,
+            TAG_DOWNLOAD_CHUNK,
+            TAG_READ_RANGE_RESPONSE,
+            TAG_APPEND_CHUNKS,
+            TAG_REPLACE_CHUNK,
+            TAG_REPLACE_ACK,
+        ] {
+            assert!(truncated(read_announced(tag, 500)), "tag {:#04x}", tag);
+            assert!(over(read_announced(tag, 501)), "tag {:#04x}", tag);
+        }
+        for tag in [TAG_HELLO, TAG_CHALLENGE, TAG_APPEND_ACK, TAG_VERSIONS, TAG_ERROR, 0x0f] {
+            assert!(truncated(read_announced(tag, 100)), "tag {:#04x}", tag);
+            assert!(over(read_announced(tag, 101)), "tag {:#04x}", tag);
+        }
+    }
+
+    #[test]
+    fn the_largest_upload_allowed_fills_a_store_frame() {
+        let limits = small_limits();
+        let len = (0..).take_while(|&len| limits.check_store(len).is_ok()).last().unwrap();
+        let store = Message::Store {
+            chunking: Chunking::content_defined(8192),
+            hasher: TreeHasher::default(),
+            data: vec![0; len],
+        };
+        assert_eq!(store.encode().len(), limits.max_store_frame);
+        assert!(Message::decode(&store.encode(), &limits).is_ok());
+    }
+
+    #[test]
+    fn counts_are_checked_before_decoding_items() {
+        let limits = small_limits();
+        let over = |message: Message| matches!(Message::decode(&message.encode(), &limits), Err(Error::OutOfRange(_)));
+        let object_id = ObjectId([7; 16]);
+        let hasher = TreeHasher::default();
+
+        let challenge =
+            |count| Message::Challenge { object_id, root: [1; 32], indices: (0..count).collect(), nonce: None };
+        assert!(Message::decode(&challenge(4).encode(), &limits).is_ok());
+        assert!(over(challenge(5)));
+        assert!(over(Message::AppendChunks { object_id, root: [1; 32], chunks: vec![vec![1]; 9] }));
+        assert!(over(Message::ConsistencyProof { hasher, leaf_count: 9, root: [1; 32], proof: vec![[2; 32]; 9] }));
+        let version = VersionInfo { root: [1; 32], leaf_count: 1, created_at: 0 };
+        assert!(over(Message::Versions { versions: vec![version; 9] }));
+
+        // a forged count is refused even though the items it announces are missing
+        let mut frame = vec![TAG_APPEND_CHUNKS];
+        frame.extend_from_slice(&[7; 16]);
+        frame.extend_from_slice(&[1; 32]);
+        frame.extend_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(Message::decode(&frame, &limits), Err(Error::OutOfRange(_))));
+    }
+}
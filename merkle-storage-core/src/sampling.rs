@@ -0,0 +1,113 @@
+//! How many leaves an audit has to challenge.
+//!
+//! If a server has lost or altered a fraction `f` of an object's `n` chunks,
+//! that is `m = ceil(f * n)` chunks, an audit of `k` distinct random leaves
+//! misses all of them with probability
+//! `C(n - m, k) / C(n, k) = prod_{i < k} (n - m - i) / (n - i)`,
+//! so it detects the damage with one minus that.
+
+/// Number of chunks a `corrupted_fraction` of `leaf_count` amounts to; any
+/// positive fraction means at least one chunk of a non-empty object.
+fn corrupted_leaves(leaf_count: usize, corrupted_fraction: f64) -> usize {
+    if corrupted_fraction <= 0.0 || leaf_count == 0 {
+        return 0;
+    }
+    ((corrupted_fraction * leaf_count as f64).ceil() as usize).clamp(1, leaf_count)
+}
+
+/// Probability that challenging `sample_size` distinct random leaves hits at
+/// least one damaged chunk.
+pub fn detection_probability(leaf_count: usize, corrupted_fraction: f64, sample_size: usize) -> f64 {
+    let corrupted = corrupted_leaves(leaf_count, corrupted_fraction);
+    let intact = leaf_count - corrupted;
+    let mut miss = 1.0;
+    for i in 0..sample_size.min(leaf_count) {
+        if i >= intact {
+            return 1.0;
+        }
+        miss *= (intact - i) as f64 / (leaf_count - i) as f64;
+    }
+    1.0 - miss
+}
+
+/// Smallest number of leaves to challenge so that losing `corrupted_fraction`
+/// of the chunks is detected with probability at least `confidence`. Capped
+/// at `leaf_count`, where detection is certain.
+pub fn sample_size(leaf_count: usize, corrupted_fraction: f64, confidence: f64) -> usize {
+    let corrupted = corrupted_leaves(leaf_count, corrupted_fraction);
+    if corrupted == 0 {
+        return leaf_count;
+    }
+    let intact = leaf_count - corrupted;
+    let mut miss = 1.0;
+    for k in 0..intact {
+        if 1.0 - miss >= confidence {
+            return k;
+        }
+        miss *= (intact - k) as f64 / (leaf_count - k) as f64;
+    }
+    if 1.0 - miss >= confidence {
+        intact
+    } else {
+        // one more draw than there are intact chunks cannot miss
+        intact + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-12
+    }
+
+    #[test]
+    fn any_damage_is_at_least_one_chunk() {
+        assert_eq!(corrupted_leaves(100, 0.0), 0);
+        assert_eq!(corrupted_leaves(100, 0.0001), 1);
+        assert_eq!(corrupted_leaves(100, 0.015), 2);
+        assert_eq!(corrupted_leaves(100, 2.0), 100);
+        assert_eq!(corrupted_leaves(0, 0.5), 0);
+    }
+
+    #[test]
+    fn detection_probability_matches_the_hypergeometric_miss() {
+        // one damaged chunk out of 100 is hit by k draws with probability k / 100
+        for k in 0..=100 {
+            assert!(close(detection_probability(100, 0.01, k), k as f64 / 100.0), "{} draws", k);
+        }
+        // two out of four: missing both with two draws is 2/4 * 1/3
+        assert!(close(detection_probability(4, 0.5, 2), 1.0 - 1.0 / 6.0));
+        assert_eq!(detection_probability(4, 0.5, 3), 1.0);
+        assert_eq!(detection_probability(10, 0.5, 1000), 1.0);
+        assert_eq!(detection_probability(10, 0.0, 10), 0.0);
+    }
+
+    #[test]
+    fn sample_size_is_the_smallest_that_reaches_the_confidence() {
+        for leaf_count in [1, 2, 7, 100, 1000, 100_000] {
+            for corrupted_fraction in [0.001, 0.01, 0.1, 0.5] {
+                for confidence in [0.5, 0.9, 0.999] {
+                    let k = sample_size(leaf_count, corrupted_fraction, confidence);
+                    assert!(k >= 1 && k <= leaf_count);
+                    assert!(detection_probability(leaf_count, corrupted_fraction, k) >= confidence);
+                    assert!(detection_probability(leaf_count, corrupted_fraction, k - 1) < confidence);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn large_objects_need_a_bounded_sample() {
+        // the classic figure: 1% damage caught with 99.9% confidence
+        let k = sample_size(1_000_000, 0.01, 0.999);
+        assert!((680..=690).contains(&k), "{} leaves", k);
+    }
+
+    #[test]
+    fn no_damage_means_challenging_everything() {
+        assert_eq!(sample_size(50, 0.0, 0.999), 50);
+        assert_eq!(sample_size(0, 0.01, 0.999), 0);
+    }
+}
@@ -0,0 +1,317 @@
+use rs_merkle::{MerkleProof, MerkleTree};
+
+use crate::hasher::{with_tree_hasher, MerkleHasher, TreeHasher};
+
+/// Merkle tree of an object, built with the object's [`TreeHasher`].
+pub struct ObjectTree(Box<dyn AnyTree>);
+
+trait AnyTree {
+    fn root(&self) -> Option<[u8; 32]>;
+    fn proof(&self, indices: &[usize]) -> Vec<[u8; 32]>;
+    fn append(&mut self, leaves: &[[u8; 32]]);
+}
+
+impl<H: MerkleHasher> AnyTree for MerkleTree<H> {
+    fn root(&self) -> Option<[u8; 32]> {
+        MerkleTree::root(self)
+    }
+
+    fn proof(&self, indices: &[usize]) -> Vec<[u8; 32]> {
+        MerkleTree::proof(self, indices).proof_hashes().to_vec()
+    }
+
+    fn append(&mut self, leaves: &[[u8; 32]]) {
+        MerkleTree::append(self, &mut leaves.to_vec());
+        self.commit();
+    }
+}
+
+impl ObjectTree {
+    pub fn root(&self) -> Option<[u8; 32]> {
+        self.0.root()
+    }
+
+    /// Adds leaves at the end and commits them.
+    pub fn append(&mut self, leaves: &[[u8; 32]]) {
+        self.0.append(leaves)
+    }
+}
+
+pub fn compute_merkle_tree(tree_hasher: TreeHasher, hashed_leaves: &[[u8; 32]]) -> ObjectTree {
+    with_tree_hasher!(tree_hasher, H => ObjectTree(Box::new(MerkleTree::<H>::from_leaves(hashed_leaves))))
+}
+
+/// Hashes of one multi-proof for the leaves at `indices_to_prove`.
+pub fn compute_merkle_proof(merkle_tree: &ObjectTree, indices_to_prove: &[usize]) -> Vec<[u8; 32]> {
+    merkle_tree.0.proof(indices_to_prove)
+}
+
+/// Checks that `leaves_to_prove`, sitting at `indices_to_prove` of a tree with
+/// `leaves_len` leaves, hash up to `merkle_root`.
+pub fn verify_merkle_proof(
+    tree_hasher: TreeHasher,
+    proof_hashes: &[[u8; 32]],
+    merkle_root: [u8; 32],
+    indices_to_prove: &[usize],
+    leaves_to_prove: &[[u8; 32]],
+    leaves_len: usize,
+) -> bool {
+    with_tree_hasher!(tree_hasher, H => {
+        MerkleProof::<H>::new(proof_hashes.to_vec()).verify(merkle_root, indices_to_prove, leaves_to_prove, leaves_len)
+    })
+}
+
+/// Root of the tree of `leaves_len` leaves in which `leaves` sit at
+/// `indices_to_prove`, with the rest of the tree given by `proof_hashes`.
+/// Used with a proof checked against the old root and a changed leaf to find
+/// the root after an in-place update.
+pub fn compute_root(
+    tree_hasher: TreeHasher,
+    proof_hashes: &[[u8; 32]],
+    indices_to_prove: &[usize],
+    leaves: &[[u8; 32]],
+    leaves_len: usize,
+) -> Option<[u8; 32]> {
+    with_tree_hasher!(tree_hasher, H => {
+        MerkleProof::<H>::new(proof_hashes.to_vec()).root(indices_to_prove, leaves, leaves_len).ok()
+    })
+}
+
+/// Root after appending `new_leaves` to a tree whose last leaf is
+/// `last_leaf`, at index `leaves_len - 1`. The proof of the last leaf only
+/// holds roots of complete subtrees to its left, which appending leaves
+/// does not touch, so it also proves the last leaf and the new ones in the
+/// extended tree. `None` for an empty tree, which has no last leaf.
+pub fn compute_appended_root(
+    tree_hasher: TreeHasher,
+    last_leaf_proof: &[[u8; 32]],
+    last_leaf: [u8; 32],
+    leaves_len: usize,
+    new_leaves: &[[u8; 32]],
+) -> Option<[u8; 32]> {
+    if leaves_len == 0 {
+        return None;
+    }
+    let indices: Vec<usize> = (leaves_len - 1..leaves_len + new_leaves.len()).collect();
+    let mut leaves = Vec::with_capacity(indices.len());
+    leaves.push(last_leaf);
+    leaves.extend_from_slice(new_leaves);
+    compute_root(tree_hasher, last_leaf_proof, &indices, &leaves, leaves_len + new_leaves.len())
+}
+
+// ==================== CONSISTENCY PROOFS ====================
+//
+// The trees built here pair nodes level by level and carry an odd node up
+// unchanged, which gives them the same shape as RFC 6962 trees: the root of
+// `n` leaves joins the subtree of the first `k` leaves, `k` the largest power
+// of two below `n`, with the subtree of the rest. So the consistency proofs
+// of RFC 6962 (section 2.1.2) and their check from RFC 9162 (section
+// 2.1.4.2) apply unchanged.
+
+/// Proves that the tree of the first `old_len` of `leaves` is a prefix of
+/// the tree of all of them. `old_len` must be in `1..=leaves.len()`; there
+/// is nothing to prove otherwise, and the proof comes back empty.
+pub fn compute_consistency_proof(tree_hasher: TreeHasher, leaves: &[[u8; 32]], old_len: usize) -> Vec<[u8; 32]> {
+    let mut proof = Vec::new();
+    if old_len == 0 || old_len > leaves.len() {
+        return proof;
+    }
+    subproof(tree_hasher, old_len, leaves, true, &mut proof);
+    proof
+}
+
+fn subproof(tree_hasher: TreeHasher, m: usize, leaves: &[[u8; 32]], complete: bool, proof: &mut Vec<[u8; 32]>) {
+    let n = leaves.len();
+    if m == n {
+        if !complete {
+            proof.push(subtree_root(tree_hasher, leaves));
+        }
+        return;
+    }
+    let k = split_point(n);
+    if m <= k {
+        subproof(tree_hasher, m, &leaves[..k], complete, proof);
+        proof.push(subtree_root(tree_hasher, &leaves[k..]));
+    } else {
+        subproof(tree_hasher, m - k, &leaves[k..], false, proof);
+        proof.push(subtree_root(tree_hasher, &leaves[..k]));
+    }
+}
+
+/// Largest power of two strictly below `n`, for `n > 1`.
+fn split_point(n: usize) -> usize {
+    1 << (usize::BITS - 1 - (n - 1).leading_zeros())
+}
+
+fn subtree_root(tree_hasher: TreeHasher, leaves: &[[u8; 32]]) -> [u8; 32] {
+    // a non-empty slice always has a root
+    compute_merkle_tree(tree_hasher, leaves).root().unwrap_or_default()
+}
+
+/// Checks that the tree of `new_len` leaves with root `new_root` extends
+/// the tree of `old_len` leaves with root `old_root`, i.e. that leaves were
+/// only appended in between.
+pub fn verify_consistency_proof(
+    tree_hasher: TreeHasher,
+    proof: &[[u8; 32]],
+    old_root: [u8; 32],
+    old_len: usize,
+    new_root: [u8; 32],
+    new_len: usize,
+) -> bool {
+    with_tree_hasher!(tree_hasher, H => verify_consistency::<H>(proof, old_root, old_len, new_root, new_len))
+}
+
+fn verify_consistency<H: MerkleHasher>(
+    proof: &[[u8; 32]],
+    old_root: [u8; 32],
+    old_len: usize,
+    new_root: [u8; 32],
+    new_len: usize,
+) -> bool {
+    if old_len == 0 || old_len > new_len {
+        return false;
+    }
+    if old_len == new_len {
+        return proof.is_empty() && old_root == new_root;
+    }
+
+    let mut path = proof.iter();
+    let mut first = old_len - 1;
+    let mut second = new_len - 1;
+    while first & 1 == 1 {
+        first >>= 1;
+        second >>= 1;
+    }
+
+    // when the old tree is a complete subtree of the new one, its root is
+    // where both paths start; otherwise the proof starts with that node
+    let start = if old_len.is_power_of_two() { Some(&old_root) } else { path.next() };
+    let (mut old_hash, mut new_hash) = match start {
+        Some(hash) => (*hash, *hash),
+        None => return false,
+    };
+    for hash in path {
+        if second == 0 {
+            return false;
+        }
+        if first & 1 == 1 || first == second {
+            old_hash = H::concat_and_hash(hash, Some(&old_hash));
+            new_hash = H::concat_and_hash(hash, Some(&new_hash));
+            while first & 1 == 0 && first != 0 {
+                first >>= 1;
+                second >>= 1;
+            }
+        } else {
+            new_hash = H::concat_and_hash(&new_hash, Some(hash));
+        }
+        first >>= 1;
+        second >>= 1;
+    }
+    old_hash == old_root && new_hash == new_root && second == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hasher::HashAlgorithm;
+    use crate::leaves::hash_leaf;
+
+    const HASHERS: [TreeHasher; 2] = [
+        TreeHasher { algorithm: HashAlgorithm::Sha256, domain_separated: false },
+        TreeHasher { algorithm: HashAlgorithm::Blake3, domain_separated: true },
+    ];
+
+    fn leaves(tree_hasher: TreeHasher, n: usize) -> Vec<[u8; 32]> {
+        (0..n).map(|i| hash_leaf(tree_hasher, &i.to_be_bytes())).collect()
+    }
+
+    fn root(tree_hasher: TreeHasher, leaves: &[[u8; 32]]) -> [u8; 32] {
+        compute_merkle_tree(tree_hasher, leaves).root().unwrap()
+    }
+
+    #[test]
+    fn consistency_proofs_verify_for_every_prefix() {
+        for tree_hasher in HASHERS {
+            let leaves = leaves(tree_hasher, 40);
+            for n in 1..=leaves.len() {
+                let new_root = root(tree_hasher, &leaves[..n]);
+                for m in 1..=n {
+                    let proof = compute_consistency_proof(tree_hasher, &leaves[..n], m);
+                    let old_root = root(tree_hasher, &leaves[..m]);
+                    assert!(
+                        verify_consistency_proof(tree_hasher, &proof, old_root, m, new_root, n),
+                        "{} leaves extending {} with {}",
+                        n,
+                        m,
+                        tree_hasher
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn consistency_proofs_reject_tampering() {
+        for tree_hasher in HASHERS {
+            let leaves = leaves(tree_hasher, 24);
+            for n in 2..=leaves.len() {
+                let new_root = root(tree_hasher, &leaves[..n]);
+                for m in 1..n {
+                    let old_root = root(tree_hasher, &leaves[..m]);
+                    let proof = compute_consistency_proof(tree_hasher, &leaves[..n], m);
+                    for i in 0..proof.len() {
+                        let mut tampered = proof.clone();
+                        tampered[i][0] ^= 1;
+                        assert!(!verify_consistency_proof(tree_hasher, &tampered, old_root, m, new_root, n));
+                    }
+                    let truncated = &proof[..proof.len() - 1];
+                    assert!(!verify_consistency_proof(tree_hasher, truncated, old_root, m, new_root, n));
+                    let mut extended = proof.clone();
+                    extended.push(new_root);
+                    assert!(!verify_consistency_proof(tree_hasher, &extended, old_root, m, new_root, n));
+                    assert!(!verify_consistency_proof(tree_hasher, &proof, new_root, n, old_root, m));
+                    assert!(!verify_consistency_proof(tree_hasher, &proof, old_root, m, old_root, n));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn consistency_of_equal_trees_needs_no_proof() {
+        let tree_hasher = TreeHasher::default();
+        let leaves = leaves(tree_hasher, 5);
+        let root = root(tree_hasher, &leaves);
+        assert!(compute_consistency_proof(tree_hasher, &leaves, 5).is_empty());
+        assert!(verify_consistency_proof(tree_hasher, &[], root, 5, root, 5));
+        assert!(!verify_consistency_proof(tree_hasher, &[root], root, 5, root, 5));
+    }
+
+    #[test]
+    fn empty_trees_are_refused() {
+        let tree_hasher = TreeHasher::default();
+        let leaves = leaves(tree_hasher, 3);
+        let root = root(tree_hasher, &leaves);
+        assert!(compute_consistency_proof(tree_hasher, &[], 0).is_empty());
+        assert!(compute_consistency_proof(tree_hasher, &leaves, 0).is_empty());
+        assert!(compute_consistency_proof(tree_hasher, &leaves, 4).is_empty());
+        assert!(!verify_consistency_proof(tree_hasher, &[], [0; 32], 0, root, 3));
+        assert!(!verify_consistency_proof(tree_hasher, &[], [0; 32], 0, [0; 32], 0));
+        assert_eq!(compute_appended_root(tree_hasher, &[], [0; 32], 0, &leaves), None);
+    }
+
+    #[test]
+    fn appended_roots_match_the_extended_tree() {
+        for tree_hasher in HASHERS {
+            let leaves = leaves(tree_hasher, 40);
+            for n in 1..=32 {
+                let tree = compute_merkle_tree(tree_hasher, &leaves[..n]);
+                let proof = compute_merkle_proof(&tree, &[n - 1]);
+                for k in 1..=8 {
+                    let appended = compute_appended_root(tree_hasher, &proof, leaves[n - 1], n, &leaves[n..n + k]);
+                    assert_eq!(appended, Some(root(tree_hasher, &leaves[..n + k])), "{} + {} leaves", n, k);
+                }
+            }
+        }
+    }
+}
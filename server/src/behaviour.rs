@@ -0,0 +1,28 @@
+//! How a server deviates from the protocol, if at all.
+//!
+//! Every request passes through [`Behaviour::request`] before it is handled
+//! and every answer through [`Behaviour::response`] before it is sent, so a
+//! misbehaving server can be built on the same session logic as the honest
+//! one. Handshakes and `Error` frames are never touched.
+
+use merkle_storage_core::protocol::Message;
+
+use crate::store::Store;
+
+pub trait Behaviour: Send + Sync {
+    /// The request the server acts on in place of `request`.
+    fn request(&self, _store: &Store, request: Message) -> Message {
+        request
+    }
+
+    /// The answer sent in place of `response`, or nothing at all.
+    fn response(&self, response: Message) -> Option<Message> {
+        Some(response)
+    }
+}
+
+/// Handles every request as asked and answers truthfully.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Honest;
+
+impl Behaviour for Honest {}
@@ -0,0 +1,467 @@
+//! Server configuration: a TOML file whose settings can each be overridden
+//! by a command-line flag or an environment variable, in that order of
+//! precedence, with defaults for everything left out.
+//!
+//! ```toml
+//! listen = "0.0.0.0:8080"
+//! data-dir = "/var/lib/merkle-storage"
+//! log-level = "info"
+//! max-connections = 256
+//! frame-timeout-secs = 30
+//! session-timeout-secs = 3600
+//! allowed-hashers = ["sha256", "blake3"]
+//!
+//! [chunk-size]
+//! min = 256
+//! max = 1048576
+//!
+//! [limits]
+//! max-store-frame = 268435456
+//! max-challenge = 4096
+//!
+//! [quota]
+//! max-objects = 100000
+//! max-bytes = 1099511627776
+//! ```
+
+use std::fs;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::Args;
+use log::LevelFilter;
+use merkle_storage_core::{Chunking, Error, HashAlgorithm, Limits, Result, TreeHasher};
+use serde::{Deserialize, Serialize};
+
+use crate::store::Usage;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Address the server listens on.
+    pub listen: String,
+    /// Directory the store lives in.
+    pub data_dir: PathBuf,
+    /// Most detailed log messages printed.
+    pub log_level: LevelFilter,
+    /// Connections served at once; further clients wait to be accepted.
+    pub max_connections: usize,
+    /// Longest wait for one frame to be read or written, and for the next
+    /// request of an idle client.
+    pub frame_timeout_secs: u64,
+    /// Longest a session may last; a request in progress is finished.
+    pub session_timeout_secs: u64,
+    /// Hash algorithms uploads may build their trees with.
+    pub allowed_hashers: Vec<HashAlgorithm>,
+    /// Chunk sizes uploads may ask for: the size of fixed-size chunks, and
+    /// the minimum, average and maximum size of content-defined ones.
+    pub chunk_size: ChunkSizes,
+    /// Limits on what clients send.
+    pub limits: Limits,
+    pub quota: Quota,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChunkSizes {
+    pub min: usize,
+    pub max: usize,
+}
+
+/// Bounds on the whole store, unbounded when left out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Quota {
+    /// Most objects the store may hold.
+    pub max_objects: Option<usize>,
+    /// Most bytes of chunks the store may hold.
+    pub max_bytes: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: "127.0.0.1:8080".to_string(),
+            data_dir: PathBuf::from("data"),
+            log_level: LevelFilter::Info,
+            max_connections: 256,
+            frame_timeout_secs: 30,
+            session_timeout_secs: 60 * 60,
+            allowed_hashers: HashAlgorithm::ALL.to_vec(),
+            chunk_size: ChunkSizes::default(),
+            limits: Limits::default(),
+            quota: Quota::default(),
+        }
+    }
+}
+
+impl Default for ChunkSizes {
+    fn default() -> ChunkSizes {
+        ChunkSizes { min: 1, max: 4 << 20 }
+    }
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| Error::Io(io::Error::new(e.kind(), format!("{}: {}", path.display(), e))))?;
+        toml::from_str(&text).map_err(|e| Error::InvalidInput(format!("{}: {}", path.display(), e.message())))
+    }
+
+    /// The configuration as a TOML file would spell it out.
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string(self).map_err(|e| Error::InvalidInput(format!("cannot write the configuration as TOML: {}", e)))
+    }
+
+    /// Sends log messages up to the configured level to stderr.
+    pub fn init_logging(&self) {
+        env_logger::Builder::new().filter_level(self.log_level).format_target(false).init();
+    }
+
+    pub fn frame_timeout(&self) -> Duration {
+        Duration::from_secs(self.frame_timeout_secs)
+    }
+
+    pub fn session_timeout(&self) -> Duration {
+        Duration::from_secs(self.session_timeout_secs)
+    }
+
+    /// Checks the settings make sense together, without touching the network
+    /// or the store.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |message: String| Err(Error::InvalidInput(message));
+        match self.listen.to_socket_addrs().map(|mut addresses| addresses.next()) {
+            Ok(Some(_)) => {}
+            Ok(None) => return invalid(format!("listen address {} resolves to nothing", self.listen)),
+            Err(e) => return invalid(format!("listen address {}: {}", self.listen, e)),
+        }
+        if self.data_dir.exists() && !self.data_dir.is_dir() {
+            return invalid(format!("data-dir {} is not a directory", self.data_dir.display()));
+        }
+        if self.max_connections == 0 || u32::try_from(self.max_connections).is_err() {
+            return invalid(format!("max-connections must be between 1 and {}", u32::MAX));
+        }
+        if self.frame_timeout_secs == 0 || self.session_timeout_secs == 0 {
+            return invalid("timeouts must be at least a second".to_string());
+        }
+        if self.allowed_hashers.is_empty() {
+            return invalid("allowed-hashers must name at least one hash algorithm".to_string());
+        }
+        let Limits { max_store_frame, max_chunk_frame, max_control_frame, max_leaves, max_challenge } = self.limits;
+        if [max_store_frame, max_chunk_frame, max_control_frame, max_leaves, max_challenge].contains(&0) {
+            return invalid("limits must all be positive".to_string());
+        }
+        if max_challenge > max_leaves {
+            return invalid(format!("max-challenge {} exceeds max-leaves {}", max_challenge, max_leaves));
+        }
+        let ChunkSizes { min, max } = self.chunk_size;
+        if min == 0 || min > max {
+            return invalid(format!("chunk-size bounds {}..={} are empty", min, max));
+        }
+        // a challenge of a single chunk of the largest size, with its proof,
+        // has to fit a chunk frame
+        if self.limits.max_challenge_for(max, max_leaves) == 0 {
+            return invalid(format!(
+                "a challenged chunk of {} bytes and its proof do not fit frames of max-chunk-frame {} bytes",
+                max, max_chunk_frame
+            ));
+        }
+        Ok(())
+    }
+
+    /// Refuses uploads whose tree or chunks this server does not accept.
+    pub fn check_upload(&self, chunking: Chunking, hasher: TreeHasher) -> Result<()> {
+        if !self.allowed_hashers.contains(&hasher.algorithm) {
+            let names: Vec<&str> = self.allowed_hashers.iter().map(HashAlgorithm::name).collect();
+            return Err(Error::InvalidInput(format!(
+                "{} is not allowed here, use one of {}",
+                hasher.algorithm,
+                names.join(", ")
+            )));
+        }
+        let sizes = match chunking {
+            Chunking::Fixed { size } => vec![size],
+            Chunking::ContentDefined { min, avg, max } => vec![min, avg, max],
+        };
+        let ChunkSizes { min, max } = self.chunk_size;
+        match sizes.into_iter().find(|size| !(min..=max).contains(size)) {
+            Some(size) => Err(Error::OutOfRange(format!(
+                "chunks of {} bytes are outside the {}..={} bytes this server accepts",
+                size, min, max
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Quota {
+    /// Refuses to store `objects` more objects and `bytes` more bytes of
+    /// chunks on top of `usage` if that would exceed the quota.
+    pub fn check(&self, usage: Usage, objects: usize, bytes: usize) -> Result<()> {
+        if let Some(max_objects) = self.max_objects {
+            if usage.objects.saturating_add(objects) > max_objects {
+                return Err(Error::OutOfRange(format!("the store is full, it holds {} objects at most", max_objects)));
+            }
+        }
+        if let Some(max_bytes) = self.max_bytes {
+            if usage.bytes.saturating_add(bytes as u64) > max_bytes {
+                return Err(Error::OutOfRange(format!(
+                    "{} more bytes would exceed the quota of {} bytes, {} are in use",
+                    bytes, max_bytes, usage.bytes
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Flags and environment variables overriding the configuration file.
+#[derive(Debug, Args)]
+pub struct ConfigArgs {
+    /// TOML configuration file
+    #[arg(long, env = "MERKLE_STORAGE_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Validate the configuration and exit
+    #[arg(long)]
+    pub check_config: bool,
+
+    /// Address to listen on
+    #[arg(long, env = "MERKLE_STORAGE_LISTEN")]
+    pub listen: Option<String>,
+
+    /// Directory to keep the store in
+    #[arg(long, env = "MERKLE_STORAGE_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+
+    /// Most detailed log messages printed: off, error, warn, info, debug or trace
+    #[arg(long, env = "MERKLE_STORAGE_LOG_LEVEL")]
+    pub log_level: Option<LevelFilter>,
+
+    /// Connections served at once
+    #[arg(long, env = "MERKLE_STORAGE_MAX_CONNECTIONS")]
+    pub max_connections: Option<usize>,
+
+    /// Seconds one frame may take to arrive or leave
+    #[arg(long, env = "MERKLE_STORAGE_FRAME_TIMEOUT_SECS")]
+    pub frame_timeout_secs: Option<u64>,
+
+    /// Seconds a session may last
+    #[arg(long, env = "MERKLE_STORAGE_SESSION_TIMEOUT_SECS")]
+    pub session_timeout_secs: Option<u64>,
+
+    /// Hash algorithms uploads may use, comma-separated
+    #[arg(long, env = "MERKLE_STORAGE_ALLOWED_HASHERS", value_delimiter = ',')]
+    pub allowed_hashers: Option<Vec<HashAlgorithm>>,
+
+    /// Most objects the store may hold
+    #[arg(long, env = "MERKLE_STORAGE_MAX_OBJECTS")]
+    pub max_objects: Option<usize>,
+
+    /// Most bytes of chunks the store may hold
+    #[arg(long, env = "MERKLE_STORAGE_MAX_BYTES")]
+    pub max_bytes: Option<u64>,
+
+    /// Smallest chunk size uploads may ask for
+    #[arg(long, env = "MERKLE_STORAGE_MIN_CHUNK_SIZE")]
+    pub min_chunk_size: Option<usize>,
+
+    /// Largest chunk size uploads may ask for
+    #[arg(long, env = "MERKLE_STORAGE_MAX_CHUNK_SIZE")]
+    pub max_chunk_size: Option<usize>,
+
+    /// Largest Store frame, in bytes
+    #[arg(long, env = "MERKLE_STORAGE_MAX_STORE_FRAME")]
+    pub max_store_frame: Option<usize>,
+
+    /// Largest frame of the other requests carrying chunks, in bytes
+    #[arg(long, env = "MERKLE_STORAGE_MAX_CHUNK_FRAME")]
+    pub max_chunk_frame: Option<usize>,
+
+    /// Largest frame of any other request, in bytes
+    #[arg(long, env = "MERKLE_STORAGE_MAX_CONTROL_FRAME")]
+    pub max_control_frame: Option<usize>,
+
+    /// Most leaves an object, or chunks and hashes a request, may have
+    #[arg(long, env = "MERKLE_STORAGE_MAX_LEAVES")]
+    pub max_leaves: Option<usize>,
+
+    /// Most leaves one challenge may ask for
+    #[arg(long, env = "MERKLE_STORAGE_MAX_CHALLENGE")]
+    pub max_challenge: Option<usize>,
+}
+
+impl ConfigArgs {
+    /// The configuration file, or `defaults` without one, with the flags and
+    /// environment variables given applied on top.
+    pub fn load(&self, defaults: Config) -> Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => defaults,
+        };
+        if let Some(listen) = &self.listen {
+            config.listen = listen.clone();
+        }
+        if let Some(data_dir) = &self.data_dir {
+            config.data_dir = data_dir.clone();
+        }
+        if let Some(log_level) = self.log_level {
+            config.log_level = log_level;
+        }
+        if let Some(max_connections) = self.max_connections {
+            config.max_connections = max_connections;
+        }
+        if let Some(frame_timeout_secs) = self.frame_timeout_secs {
+            config.frame_timeout_secs = frame_timeout_secs;
+        }
+        if let Some(session_timeout_secs) = self.session_timeout_secs {
+            config.session_timeout_secs = session_timeout_secs;
+        }
+        if let Some(allowed_hashers) = &self.allowed_hashers {
+            config.allowed_hashers = allowed_hashers.clone();
+        }
+        if self.max_objects.is_some() {
+            config.quota.max_objects = self.max_objects;
+        }
+        if self.max_bytes.is_some() {
+            config.quota.max_bytes = self.max_bytes;
+        }
+        let overrides = [
+            (self.min_chunk_size, &mut config.chunk_size.min),
+            (self.max_chunk_size, &mut config.chunk_size.max),
+            (self.max_store_frame, &mut config.limits.max_store_frame),
+            (self.max_chunk_frame, &mut config.limits.max_chunk_frame),
+            (self.max_control_frame, &mut config.limits.max_control_frame),
+            (self.max_leaves, &mut config.limits.max_leaves),
+            (self.max_challenge, &mut config.limits.max_challenge),
+        ];
+        for (value, setting) in overrides {
+            if let Some(value) = value {
+                *setting = value;
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Debug, Parser)]
+    struct Cli {
+        #[command(flatten)]
+        args: ConfigArgs,
+    }
+
+    /// Writes `text` to a configuration file of its own and loads it with
+    /// the given flags on top.
+    fn load(text: &str, flags: &[&str]) -> Result<Config> {
+        let path = env::temp_dir().join(format!("merkle-storage-config-{:08x}.toml", rand::random::<u32>()));
+        fs::write(&path, text)?;
+        let path_arg = path.display().to_string();
+        let cli = Cli::parse_from(["server", "--config", &path_arg].iter().chain(flags));
+        let config = cli.args.load(Config::default());
+        let _ = fs::remove_file(&path);
+        config
+    }
+
+    fn invalid(config: &Config) -> bool {
+        matches!(config.validate(), Err(Error::InvalidInput(_)))
+    }
+
+    #[test]
+    fn a_missing_file_is_named() {
+        let error = Config::load("no/such/merkle-storage.toml").unwrap_err();
+        assert!(matches!(error, Error::Io(_)));
+        assert!(error.to_string().contains("no/such/merkle-storage.toml"), "{}", error);
+    }
+
+    #[test]
+    fn flags_win_over_the_file() {
+        let text = "max-connections = 8\n[chunk-size]\nmax = 65536\n[limits]\nmax-leaves = 1000\nmax-challenge = 10\n";
+        let config = load(text, &[]).unwrap();
+        assert_eq!(config.max_connections, 8);
+        assert_eq!(config.chunk_size, ChunkSizes { min: 1, max: 65536 });
+        assert_eq!(config.limits.max_leaves, 1000);
+
+        let flags = ["--max-connections", "9", "--max-chunk-size", "4096", "--max-leaves", "2000"];
+        let config = load(text, &flags).unwrap();
+        assert_eq!(config.max_connections, 9);
+        assert_eq!(config.chunk_size, ChunkSizes { min: 1, max: 4096 });
+        assert_eq!(config.limits, Limits { max_leaves: 2000, max_challenge: 10, ..Limits::default() });
+        assert_eq!(config.listen, Config::default().listen);
+    }
+
+    #[test]
+    fn merged_settings_are_validated() {
+        assert!(matches!(load("", &["--max-challenge", "0"]), Err(Error::InvalidInput(_))));
+        assert!(matches!(load("[chunk-size]\nmin = 64\n", &["--max-chunk-size", "32"]), Err(Error::InvalidInput(_))));
+    }
+
+    #[test]
+    fn unknown_keys_are_refused() {
+        assert!(load("", &[]).is_ok());
+        for text in ["colour = \"red\"\n", "[limits]\nmax-everything = 1\n", "[chunk-size]\navg = 4096\n"] {
+            assert!(matches!(load(text, &[]), Err(Error::InvalidInput(_))), "{}", text);
+        }
+    }
+
+    #[test]
+    fn the_defaults_are_valid() {
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn zero_limits_are_refused() {
+        let zeroed: [fn(&mut Limits); 5] = [
+            |limits| limits.max_store_frame = 0,
+            |limits| limits.max_chunk_frame = 0,
+            |limits| limits.max_control_frame = 0,
+            |limits| limits.max_leaves = 0,
+            |limits| limits.max_challenge = 0,
+        ];
+        for zero in zeroed {
+            let mut config = Config::default();
+            zero(&mut config.limits);
+            assert!(invalid(&config), "{:?}", config.limits);
+        }
+    }
+
+    #[test]
+    fn challenges_larger_than_objects_are_refused() {
+        let mut config = Config::default();
+        config.limits.max_leaves = 100;
+        config.limits.max_challenge = 101;
+        assert!(invalid(&config));
+        config.limits.max_challenge = 100;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn empty_chunk_size_bounds_are_refused() {
+        for (min, max) in [(0, 4096), (4097, 4096)] {
+            let config = Config { chunk_size: ChunkSizes { min, max }, ..Config::default() };
+            assert!(invalid(&config), "{}..={}", min, max);
+        }
+        let config = Config { chunk_size: ChunkSizes { min: 4096, max: 4096 }, ..Config::default() };
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn chunks_that_cannot_be_challenged_in_one_frame_are_refused() {
+        let limits = Limits { max_chunk_frame: 1 << 20, ..Limits::default() };
+        let config = Config { chunk_size: ChunkSizes { min: 1, max: 1 << 20 }, limits, ..Config::default() };
+        assert!(invalid(&config));
+        let config = Config { chunk_size: ChunkSizes { min: 1, max: 1 << 19 }, ..config };
+        assert!(config.validate().is_ok());
+    }
+}
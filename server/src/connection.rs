@@ -0,0 +1,81 @@
+//! The socket side of a session: reading requests and writing answers with
+//! the limits and timeouts of the server applied.
+
+use std::io;
+use std::time::Duration;
+
+use merkle_storage_core::protocol::{read_message_async, write_message_async, Message};
+use merkle_storage_core::{Error, Limits, Result};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::time::{timeout, timeout_at, Instant};
+
+pub struct Connection {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    limits: Limits,
+    frame_timeout: Duration,
+    /// When the session is over, whatever the client is doing.
+    deadline: Instant,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream, limits: Limits, frame_timeout: Duration, session_timeout: Duration) -> Connection {
+        let (reader, writer) = stream.into_split();
+        Connection {
+            reader: BufReader::new(reader),
+            writer,
+            limits,
+            frame_timeout,
+            deadline: Instant::now() + session_timeout,
+        }
+    }
+
+    /// Waits for the client to start sending its next request. `false` if
+    /// it hung up instead, or the server is shutting down first.
+    pub async fn next_request(&mut self, shutdown: &mut watch::Receiver<bool>) -> Result<bool> {
+        let deadline = self.read_deadline();
+        tokio::select! {
+            biased;
+            _ = shutdown.wait_for(|&stopping| stopping) => Ok(false),
+            buffered = timeout_at(deadline, self.reader.fill_buf()) => match buffered {
+                Ok(Ok(buffered)) => Ok(!buffered.is_empty()),
+                Ok(Err(e)) => Err(e.into()),
+                Err(_) => Err(self.timed_out()),
+            },
+        }
+    }
+
+    pub async fn receive(&mut self) -> Result<Message> {
+        let deadline = self.read_deadline();
+        match timeout_at(deadline, read_message_async(&mut self.reader, &self.limits)).await {
+            Ok(message) => message,
+            Err(_) => Err(self.timed_out()),
+        }
+    }
+
+    pub async fn send(&mut self, message: &Message) -> Result<()> {
+        match timeout(self.frame_timeout, write_message_async(&mut self.writer, message)).await {
+            Ok(sent) => sent,
+            Err(_) => Err(Error::Io(io::Error::new(io::ErrorKind::TimedOut, "the client stopped reading"))),
+        }
+    }
+
+    /// Tells the client why the session is being closed and hands the error back.
+    pub async fn reject(&mut self, error: Error) -> Error {
+        let _ = self.send(&Message::from(&error)).await;
+        error
+    }
+
+    /// A read may take one frame timeout, but never past the session deadline.
+    fn read_deadline(&self) -> Instant {
+        (Instant::now() + self.frame_timeout).min(self.deadline)
+    }
+
+    fn timed_out(&self) -> Error {
+        let what = if Instant::now() >= self.deadline { "session" } else { "read" };
+        Error::Io(io::Error::new(io::ErrorKind::TimedOut, format!("{} timed out", what)))
+    }
+}
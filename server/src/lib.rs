@@ -0,0 +1,645 @@
+pub mod behaviour;
+pub mod config;
+pub mod connection;
+pub mod store;
+
+use std::io;
+use std::sync::Arc;
+
+use log::{debug, info, warn};
+
+use merkle_storage_core::protocol::{unexpected_message, Message, VersionInfo, PROTOCOL_VERSION};
+use merkle_storage_core::{
+    compute_consistency_proof, compute_leaves, compute_merkle_proof, compute_merkle_tree, covering_chunks, hash_leaves,
+    nonce_tag, Chunking, Error, Limits, ObjectId, Result, TreeHasher,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch, Semaphore};
+
+use crate::behaviour::{Behaviour, Honest};
+use crate::config::Config;
+use crate::connection::Connection;
+use crate::store::{Store, StoredObject};
+
+/// Answers of one request waiting to be written; a handler producing them
+/// faster than the client reads blocks once this many are queued.
+const ANSWER_QUEUE: usize = 16;
+
+pub fn serve(config: &Config) -> Result<()> {
+    serve_with(config, Honest)
+}
+
+/// Serves like [`serve`], but letting `behaviour` rewrite requests and
+/// answers. Returns once SIGINT or SIGTERM has stopped the server, after the
+/// sessions still open have finished the request they were in and the store
+/// has been flushed.
+pub fn serve_with<B: Behaviour + 'static>(config: &Config, behaviour: B) -> Result<()> {
+    let store = Arc::new(Store::open(&config.data_dir)?);
+    let usage = store.usage();
+    let config = Arc::new(config.clone());
+    let behaviour: Arc<dyn Behaviour> = Arc::new(behaviour);
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let listener = TcpListener::bind(&config.listen).await?;
+        info!(
+            "Server listening on {}, storing data in {} ({} objects, {} bytes of chunks)",
+            config.listen,
+            config.data_dir.display(),
+            usage.objects,
+            usage.bytes
+        );
+        accept_loop(listener, &store, &config, behaviour).await;
+        Ok::<_, Error>(())
+    })?;
+    store.flush()?;
+    info!("Store flushed, bye");
+    Ok(())
+}
+
+/// Accepts connections until a shutdown signal, then waits for the open ones
+/// to close.
+async fn accept_loop(listener: TcpListener, store: &Arc<Store>, config: &Arc<Config>, behaviour: Arc<dyn Behaviour>) {
+    let slots = Arc::new(Semaphore::new(config.max_connections));
+    let (stop, stopping) = watch::channel(false);
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
+
+    loop {
+        // take a slot before accepting, so clients beyond the limit queue in the backlog
+        let slot = tokio::select! {
+            _ = &mut signal => break,
+            slot = Arc::clone(&slots).acquire_owned() => match slot {
+                Ok(slot) => slot,
+                Err(_) => break,
+            },
+        };
+        let (stream, peer_addr) = tokio::select! {
+            _ = &mut signal => break,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Error accepting a connection: {}", e);
+                    continue;
+                }
+            },
+        };
+        info!("New connection: {}", peer_addr);
+        let store = Arc::clone(store);
+        let config = Arc::clone(config);
+        let behaviour = Arc::clone(&behaviour);
+        let stopping = stopping.clone();
+        tokio::spawn(async move {
+            let _slot = slot;
+            match handle_client(stream, store, config, behaviour, stopping).await {
+                Ok(()) => info!("Closed connection to {}", peer_addr),
+                Err(e) => warn!("Closed connection to {}: {}", peer_addr, e),
+            }
+        });
+    }
+
+    drop(listener);
+    let open = config.max_connections - slots.available_permits();
+    info!("Shutting down, waiting for {} open connections", open);
+    let _ = stop.send(true);
+    let _ = slots.acquire_many(config.max_connections as u32).await;
+}
+
+/// Resolves on SIGINT, or SIGTERM where there is one.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            return;
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Serves one connection: after the handshake the client may upload objects
+/// and challenge any object in the store by its id, in any order, until it
+/// hangs up. Uploads and audits usually come in separate sessions. The first
+/// request that fails is answered with an `Error` frame and ends the session,
+/// as does a timeout. Once `stopping` turns true the session ends as soon as
+/// the request in progress, if any, is answered.
+pub async fn handle_client(
+    stream: TcpStream,
+    store: Arc<Store>,
+    config: Arc<Config>,
+    behaviour: Arc<dyn Behaviour>,
+    mut stopping: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut connection = Connection::new(stream, config.limits, config.frame_timeout(), config.session_timeout());
+
+    // 0. Agree on the protocol version
+    handshake(&mut connection).await?;
+
+    loop {
+        match connection.next_request(&mut stopping).await {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(e) => return Err(connection.reject(e).await),
+        }
+        let request = match connection.receive().await {
+            Ok(request) => request,
+            Err(e) => return Err(connection.reject(e).await),
+        };
+
+        // handlers read and write the store synchronously, so they run off
+        // the async workers and hand their answers over through a queue
+        let (answers, mut queued) = mpsc::channel(ANSWER_QUEUE);
+        let mut peer = Peer { answers, config: Arc::clone(&config), behaviour: Arc::clone(&behaviour) };
+        let store = Arc::clone(&store);
+        let handler = tokio::task::spawn_blocking(move || {
+            let request = peer.behaviour.request(&store, request);
+            handle_request(&mut peer, &store, request)
+        });
+        while let Some(answer) = queued.recv().await {
+            connection.send(&answer).await?;
+        }
+        let handled = handler
+            .await
+            .unwrap_or_else(|e| Err(Error::Io(io::Error::other(format!("request handler failed: {}", e)))));
+        if let Err(e) = handled {
+            return Err(connection.reject(e).await);
+        }
+    }
+}
+
+fn handle_request(peer: &mut Peer, store: &Store, request: Message) -> Result<()> {
+    match request {
+        Message::Store { chunking, hasher, data } => handle_store(peer, store, chunking, hasher, &data),
+        Message::Challenge { object_id, root, indices, nonce } => {
+            handle_challenge(peer, store, object_id, root, indices, nonce)
+        }
+        Message::Download { object_id, root, start, end } => handle_download(peer, store, object_id, root, start, end),
+        Message::ReadRange { object_id, root, offset, length } => {
+            handle_read_range(peer, store, object_id, root, offset, length)
+        }
+        Message::AppendChunks { object_id, root, chunks } => handle_append(peer, store, object_id, root, &chunks),
+        Message::ReplaceChunk { object_id, root, index, data } => {
+            handle_replace(peer, store, object_id, root, index, &data)
+        }
+        Message::ProveConsistency { object_id, old_leaf_count } => {
+            handle_consistency(peer, store, object_id, old_leaf_count)
+        }
+        Message::ListVersions { object_id } => handle_list_versions(peer, store, object_id),
+        Message::Rollback { object_id, root, target } => handle_rollback(peer, store, object_id, root, target),
+        other => Err(unexpected_message("a request", other)),
+    }
+}
+
+fn handle_store(peer: &mut Peer, store: &Store, chunking: Chunking, hasher: TreeHasher, data: &[u8]) -> Result<()> {
+    // 1. Receive the data from the client
+    debug!("Received {} bytes, chunking: {}, hasher: {}", data.len(), chunking, hasher);
+    chunking.validate()?;
+    peer.config.check_upload(chunking, hasher)?;
+    if data.is_empty() {
+        return Err(Error::InvalidInput("cannot store empty data".to_string()));
+    }
+    peer.config.quota.check(store.usage(), 1, data.len())?;
+
+    // 2. Compute Leaves
+    let chunks = compute_leaves(data, &chunking);
+    debug!("Leaves: {}", chunks.len());
+    peer.config.limits.check_leaves(chunks.len())?;
+
+    // 3. Hash Leaves
+    let leaves = hash_leaves(hasher, &chunks);
+
+    // 4. Compute Merkle Tree
+    let merkle_tree = compute_merkle_tree(hasher, &leaves);
+    let merkle_root = merkle_tree.root().unwrap_or_default();
+
+    // 5. Persist the chunks and the leaves
+    let object_id = store.put(merkle_root, chunking, hasher, &chunks)?;
+    info!("Stored object {}", object_id);
+
+    // 6. Send the object id and the Merkle Root to the client
+    peer.send(Message::RootAck { object_id, root: merkle_root })
+}
+
+fn handle_challenge(
+    peer: &mut Peer,
+    store: &Store,
+    object_id: ObjectId,
+    root: [u8; 32],
+    indices_to_prove: Vec<usize>,
+    nonce: Option<[u8; 32]>,
+) -> Result<()> {
+    // 1. Look the version of the object up
+    let object = load_version(store, object_id, root)?;
+    debug!("Received indices to prove for {}: {:?}", object_id, indices_to_prove);
+    check_challenge(&peer.config.limits, object_id, &object, &indices_to_prove)?;
+
+    // 2. Rebuild the Merkle Tree
+    let merkle_tree = compute_merkle_tree(object.hasher, &object.leaves);
+
+    // 3. Read the challenged chunks and compute one Merkle multi-proof for them
+    let chunks = indices_to_prove
+        .iter()
+        .map(|&index| store.read_chunk(object.hasher, &object.leaves[index]))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let merkle_proof = compute_merkle_proof(&merkle_tree, &indices_to_prove);
+
+    // 4. Bind the chunks to the client's nonce, if it sent one
+    let chunk_refs: Vec<&[u8]> = chunks.iter().map(Vec::as_slice).collect();
+    let tags = match nonce {
+        Some(nonce) => chunk_refs.iter().map(|chunk| nonce_tag(&nonce, chunk)).collect(),
+        None => Vec::new(),
+    };
+
+    // 5. Send the chunks themselves and the Merkle Proof to the client
+    let response = Message::ChallengeResponse {
+        hasher: object.hasher,
+        chunks,
+        proof: merkle_proof,
+        tags,
+    };
+    peer.send(response)
+}
+
+fn handle_download(
+    peer: &mut Peer,
+    store: &Store,
+    object_id: ObjectId,
+    root: [u8; 32],
+    start: usize,
+    end: usize,
+) -> Result<()> {
+    let object = load_version(store, object_id, root)?;
+    if start >= end || end > object.leaves.len() {
+        return Err(Error::OutOfRange(format!(
+            "chunks {}..{} are not a range of the {} chunks of {}",
+            start,
+            end,
+            object.leaves.len(),
+            object_id
+        )));
+    }
+    debug!("Streaming chunks {}..{} of {}", start, end, object_id);
+
+    // every chunk travels with its own proof so the client can check it on arrival
+    let merkle_tree = compute_merkle_tree(object.hasher, &object.leaves);
+    for index in start..end {
+        let data = store.read_chunk(object.hasher, &object.leaves[index])?;
+        let proof = compute_merkle_proof(&merkle_tree, &[index]);
+        peer.send(Message::DownloadChunk { hasher: object.hasher, index, data, proof })?;
+    }
+    Ok(())
+}
+
+fn handle_read_range(
+    peer: &mut Peer,
+    store: &Store,
+    object_id: ObjectId,
+    root: [u8; 32],
+    offset: usize,
+    length: usize,
+) -> Result<()> {
+    let object = load_version(store, object_id, root)?;
+    let chunk_size = match object.chunking {
+        Chunking::Fixed { size } => size,
+        Chunking::ContentDefined { .. } => {
+            return Err(Error::InvalidInput(format!(
+                "{} uses content-defined chunks, byte ranges need fixed-size chunking",
+                object_id
+            )));
+        }
+    };
+
+    // 1. Map the byte range to the chunks covering it
+    let out_of_range =
+        || Error::OutOfRange(format!("{} bytes at offset {} are not within {}", length, offset, object_id));
+    let chunks = covering_chunks(chunk_size, offset, length)
+        .filter(|chunks| chunks.end <= object.leaves.len())
+        .ok_or_else(out_of_range)?;
+    debug!("Reading {} bytes at offset {} of {}: chunks {:?}", length, offset, object_id, chunks);
+
+    // 2. Make sure the answer fits a frame before reading anything for it
+    let fitting = peer.config.limits.max_read_for(chunk_size, object.leaves.len());
+    if chunks.len() > fitting {
+        return Err(Error::OutOfRange(format!(
+            "the {} chunks covering {} bytes of {} would not fit a frame, read at most {} bytes at once",
+            chunks.len(),
+            length,
+            object_id,
+            fitting.saturating_mul(chunk_size)
+        )));
+    }
+
+    // 3. Read them, making sure the last one reaches the end of the range
+    let data = object.leaves[chunks.clone()]
+        .iter()
+        .map(|leaf| store.read_chunk(object.hasher, leaf))
+        .collect::<io::Result<Vec<_>>>()?;
+    let covered = chunks.start * chunk_size + data.iter().map(Vec::len).sum::<usize>();
+    if offset + length > covered {
+        return Err(out_of_range());
+    }
+
+    // 4. Prove all of them at once
+    let indices: Vec<usize> = chunks.clone().collect();
+    let merkle_tree = compute_merkle_tree(object.hasher, &object.leaves);
+    let proof = compute_merkle_proof(&merkle_tree, &indices);
+    peer.send(Message::ReadRangeResponse { hasher: object.hasher, first_index: chunks.start, chunks: data, proof })
+}
+
+fn handle_append(
+    peer: &mut Peer,
+    store: &Store,
+    object_id: ObjectId,
+    root: [u8; 32],
+    chunks: &[Vec<u8>],
+) -> Result<()> {
+    debug!("Appending {} chunks to {}", chunks.len(), object_id);
+    let ack = store.update(&object_id, |object| {
+        check_root(object_id, object, root)?;
+        if chunks.is_empty() {
+            return Err(Error::InvalidInput("nothing to append".to_string()));
+        }
+        peer.config.limits.check_leaves(object.leaves.len() + chunks.len())?;
+        peer.config.quota.check(store.usage(), 0, chunks.iter().map(Vec::len).sum())?;
+        let last = object.leaves.len() - 1;
+        let last_chunk = store.read_chunk(object.hasher, &object.leaves[last])?;
+        if object.chunking.check_chunk_len(last_chunk.len(), false).is_err() {
+            return Err(Error::InvalidInput(format!(
+                "the last chunk of {} is not full, appending would leave a short one in the middle",
+                object_id
+            )));
+        }
+        for (i, chunk) in chunks.iter().enumerate() {
+            object.chunking.check_chunk_len(chunk.len(), i == chunks.len() - 1)?;
+        }
+
+        // 1. Prove the old last leaf in the old tree
+        let mut merkle_tree = compute_merkle_tree(object.hasher, &object.leaves);
+        let proof = compute_merkle_proof(&merkle_tree, &[last]);
+        let last_leaf = object.leaves[last];
+
+        // 2. Persist the new chunks and extend the tree with their leaves
+        let new_leaves = chunks
+            .iter()
+            .map(|chunk| store.put_chunk(object.hasher, chunk))
+            .collect::<io::Result<Vec<_>>>()?;
+        object.leaves.extend_from_slice(&new_leaves);
+        merkle_tree.append(&new_leaves);
+        object.root = merkle_tree.root().unwrap_or_default();
+        Ok(Message::AppendAck { hasher: object.hasher, last_leaf, proof, root: object.root })
+    });
+    send_update_ack(peer, object_id, ack)
+}
+
+fn handle_replace(
+    peer: &mut Peer,
+    store: &Store,
+    object_id: ObjectId,
+    root: [u8; 32],
+    index: usize,
+    data: &[u8],
+) -> Result<()> {
+    debug!("Replacing chunk {} of {} with {} bytes", index, object_id, data.len());
+    let ack = store.update(&object_id, |object| {
+        check_root(object_id, object, root)?;
+        let leaf_count = object.leaves.len();
+        if index >= leaf_count {
+            return Err(Error::OutOfRange(format!("{} has no chunk {}, only {}", object_id, index, leaf_count)));
+        }
+        object.chunking.check_chunk_len(data.len(), index == leaf_count - 1)?;
+        peer.config.quota.check(store.usage(), 0, data.len())?;
+
+        // 1. Prove the old chunk in the old tree
+        let old_chunk = store.read_chunk(object.hasher, &object.leaves[index])?;
+        let proof = compute_merkle_proof(&compute_merkle_tree(object.hasher, &object.leaves), &[index]);
+
+        // 2. Persist the new chunk and rebuild the tree around it
+        object.leaves[index] = store.put_chunk(object.hasher, data)?;
+        object.root = compute_merkle_tree(object.hasher, &object.leaves).root().unwrap_or_default();
+        Ok(Message::ReplaceAck { hasher: object.hasher, old_chunk, proof, root: object.root })
+    });
+    send_update_ack(peer, object_id, ack)
+}
+
+fn handle_consistency(peer: &mut Peer, store: &Store, object_id: ObjectId, old_leaf_count: usize) -> Result<()> {
+    let object = load_object(store, object_id)?;
+    let leaf_count = object.leaves.len();
+    if old_leaf_count == 0 || old_leaf_count > leaf_count {
+        return Err(Error::OutOfRange(format!(
+            "{} has {} leaves, it cannot extend a tree of {}",
+            object_id, leaf_count, old_leaf_count
+        )));
+    }
+    debug!("Proving {} leaves of {} extend its first {}", leaf_count, object_id, old_leaf_count);
+
+    let proof = compute_consistency_proof(object.hasher, &object.leaves, old_leaf_count);
+    peer.send(Message::ConsistencyProof { hasher: object.hasher, leaf_count, root: object.root, proof })
+}
+
+fn handle_list_versions(peer: &mut Peer, store: &Store, object_id: ObjectId) -> Result<()> {
+    let object = load_object(store, object_id)?;
+    let versions = object
+        .versions()
+        .map(|(root, leaves, created_at)| VersionInfo { root: *root, leaf_count: leaves.len(), created_at })
+        .collect();
+    peer.send(Message::Versions { versions })
+}
+
+fn handle_rollback(
+    peer: &mut Peer,
+    store: &Store,
+    object_id: ObjectId,
+    root: [u8; 32],
+    target: [u8; 32],
+) -> Result<()> {
+    debug!("Rolling {} back to {}", object_id, hex::encode(target));
+    let ack = store.update(&object_id, |object| {
+        check_root(object_id, object, root)?;
+
+        // the old version becomes current again, the one it replaces goes to the history
+        if !object.roll_back(&target) {
+            return Err(unknown_version(object_id, target));
+        }
+        Ok(Message::RollbackAck { root: object.root, leaf_count: object.leaves.len() })
+    });
+    send_update_ack(peer, object_id, ack)
+}
+
+/// Refuses updates based on another version than the current one, so two
+/// clients cannot both change the same version and lose one of the changes.
+fn check_root(object_id: ObjectId, object: &StoredObject, root: [u8; 32]) -> Result<()> {
+    if object.root == root {
+        return Ok(());
+    }
+    Err(Error::Conflict(format!(
+        "{} is at root {}, not {}",
+        object_id,
+        hex::encode(object.root),
+        hex::encode(root)
+    )))
+}
+
+/// Refuses challenges the multi-proof cannot answer: it needs at least one
+/// leaf, each one inside the object and named once, in ascending order, and
+/// no more than fit one answer frame.
+fn check_challenge(limits: &Limits, object_id: ObjectId, object: &StoredObject, indices: &[usize]) -> Result<()> {
+    limits.check_challenge(indices.len())?;
+    let fitting = limits.max_challenge_for(object.chunking.max_chunk_len(), object.leaves.len());
+    if indices.len() > fitting {
+        return Err(Error::OutOfRange(format!(
+            "the answer to a challenge of {} leaves of {} would not fit a frame, challenge at most {} at once",
+            indices.len(),
+            object_id,
+            fitting
+        )));
+    }
+    if indices.is_empty() {
+        return Err(Error::InvalidInput("a challenge must name at least one leaf".to_string()));
+    }
+    if let Some(pair) = indices.windows(2).find(|pair| pair[0] >= pair[1]) {
+        return Err(Error::InvalidInput(format!(
+            "challenged leaves must be ascending and distinct, but {} is followed by {}",
+            pair[0], pair[1]
+        )));
+    }
+    let leaf_count = object.leaves.len();
+    match indices.last() {
+        Some(&last) if last >= leaf_count => {
+            Err(Error::OutOfRange(format!("{} has no leaf {}, only {}", object_id, last, leaf_count)))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn send_update_ack(peer: &mut Peer, object_id: ObjectId, ack: Result<Option<Message>>) -> Result<()> {
+    match ack? {
+        Some(ack) => peer.send(ack),
+        None => Err(unknown_object(object_id)),
+    }
+}
+
+fn load_object(store: &Store, object_id: ObjectId) -> Result<StoredObject> {
+    store.get(&object_id)?.ok_or_else(|| unknown_object(object_id))
+}
+
+fn load_version(store: &Store, object_id: ObjectId, root: [u8; 32]) -> Result<StoredObject> {
+    load_object(store, object_id)?.at(&root).ok_or_else(|| unknown_version(object_id, root))
+}
+
+fn unknown_object(object_id: ObjectId) -> Error {
+    Error::NotFound(format!("unknown object {}", object_id))
+}
+
+fn unknown_version(object_id: ObjectId, root: [u8; 32]) -> Error {
+    Error::NotFound(format!("{} has no retained version with root {}", object_id, hex::encode(root)))
+}
+
+async fn handshake(connection: &mut Connection) -> Result<()> {
+    let error = match connection.receive().await {
+        Ok(Message::Hello { version }) if version == PROTOCOL_VERSION => {
+            return connection.send(&Message::HelloAck { version: PROTOCOL_VERSION }).await;
+        }
+        Ok(Message::Hello { version }) => Error::Protocol(format!(
+            "unsupported protocol version {}, this server speaks version {}",
+            version, PROTOCOL_VERSION
+        )),
+        Ok(other) => unexpected_message("Hello", other),
+        Err(e @ Error::Io(_)) => return Err(e),
+        Err(e) => e,
+    };
+    Err(connection.reject(error).await)
+}
+
+/// The handler's end of a client connection: the configuration its requests
+/// are held to, the behaviour it is answered with and the queue its answers
+/// go out through.
+struct Peer {
+    answers: mpsc::Sender<Message>,
+    config: Arc<Config>,
+    behaviour: Arc<dyn Behaviour>,
+}
+
+impl Peer {
+    /// Queues an answer, waiting while the queue is full.
+    fn send(&mut self, message: Message) -> Result<()> {
+        match self.behaviour.response(message) {
+            Some(message) => self
+                .answers
+                .blocking_send(message)
+                .map_err(|_| Error::Io(io::Error::new(io::ErrorKind::BrokenPipe, "the connection is gone"))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OBJECT_ID: ObjectId = ObjectId([7; 16]);
+
+    fn object(leaf_count: usize) -> StoredObject {
+        StoredObject {
+            root: [1; 32],
+            chunking: Chunking::Fixed { size: 1024 },
+            hasher: TreeHasher::default(),
+            leaves: vec![[2; 32]; leaf_count],
+            created_at: 0,
+            history: Vec::new(),
+        }
+    }
+
+    fn check(leaf_count: usize, indices: &[usize]) -> Result<()> {
+        check_challenge(&Limits::default(), OBJECT_ID, &object(leaf_count), indices)
+    }
+
+    #[test]
+    fn ascending_distinct_leaves_of_the_object_pass() {
+        assert!(check(1, &[0]).is_ok());
+        assert!(check(2, &[0]).is_ok());
+        assert!(check(2, &[1]).is_ok());
+        assert!(check(2, &[0, 1]).is_ok());
+        assert!(check(100, &[0, 17, 42, 99]).is_ok());
+    }
+
+    #[test]
+    fn empty_challenges_are_refused() {
+        assert!(matches!(check(1, &[]), Err(Error::InvalidInput(_))));
+        assert!(matches!(check(100, &[]), Err(Error::InvalidInput(_))));
+    }
+
+    #[test]
+    fn unsorted_and_repeated_leaves_are_refused() {
+        assert!(matches!(check(2, &[1, 0]), Err(Error::InvalidInput(_))));
+        assert!(matches!(check(100, &[3, 9, 5]), Err(Error::InvalidInput(_))));
+        assert!(matches!(check(1, &[0, 0]), Err(Error::InvalidInput(_))));
+        assert!(matches!(check(100, &[3, 9, 9]), Err(Error::InvalidInput(_))));
+    }
+
+    #[test]
+    fn leaves_past_the_last_are_refused() {
+        assert!(matches!(check(1, &[1]), Err(Error::OutOfRange(_))));
+        assert!(matches!(check(2, &[2]), Err(Error::OutOfRange(_))));
+        assert!(matches!(check(2, &[0, 2]), Err(Error::OutOfRange(_))));
+        assert!(matches!(check(100, &[5, usize::MAX]), Err(Error::OutOfRange(_))));
+    }
+
+    #[test]
+    fn challenges_whose_answer_would_not_fit_a_frame_are_refused() {
+        let limits = Limits { max_chunk_frame: 10_000, ..Limits::default() };
+        let object = object(100);
+        let fitting = limits.max_challenge_for(1024, 100);
+        assert!((1..100).contains(&fitting));
+        let indices: Vec<usize> = (0..=fitting).collect();
+        assert!(check_challenge(&limits, OBJECT_ID, &object, &indices[..fitting]).is_ok());
+        assert!(matches!(check_challenge(&limits, OBJECT_ID, &object, &indices), Err(Error::OutOfRange(_))));
+
+        let limits = Limits { max_challenge: 3, ..Limits::default() };
+        assert!(check_challenge(&limits, OBJECT_ID, &object, &[0, 1, 2]).is_ok());
+        assert!(matches!(check_challenge(&limits, OBJECT_ID, &object, &[0, 1, 2, 3]), Err(Error::OutOfRange(_))));
+    }
+}
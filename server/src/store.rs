@@ -0,0 +1,550 @@
+//! On-disk store of uploaded objects.
+//!
+//! Chunks are content-addressed by their leaf hash, under `chunks/<hex>` for
+//! plain SHA-256 leaves and under `chunks/<hasher>/<hex>` for the other tree
+//! hashers, so that leaves of different hashers never share a name. So
+//! identical chunks are kept once, also across objects built with the same
+//! hasher: re-uploading an edited file with content-defined chunking only
+//! adds the chunks that changed. And every object has a small JSON record under
+//! `objects/<object id>.json` listing its leaves in order, which is all that
+//! is needed to rebuild its tree. Files are written to a temporary name and
+//! renamed into place so a crash never leaves a half-written record behind;
+//! chunks are hard-linked into place instead, which fails if the chunk is
+//! already there, so of two uploads storing the same chunk at once only one
+//! counts it. Files reach the disk for sure only once [`Store::flush`] has
+//! synced them.
+//! Updates to an object rewrite its record under the same id; they are
+//! serialised so two sessions cannot both change the same version. The
+//! record keeps the leaves of the last [`DEFAULT_HISTORY_LIMIT`] versions
+//! next to the current ones, and since chunks are never removed, proofs can
+//! still be served against any of them and the object rolled back to them.
+//! The store keeps count of its objects and chunk bytes, see [`Store::usage`],
+//! so quotas can be enforced without walking the directory on every upload.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use merkle_storage_core::{hash_leaf, Chunking, ObjectId, Result, TreeHasher};
+use serde::{Deserialize, Serialize};
+
+/// Number of earlier versions kept per object.
+pub const DEFAULT_HISTORY_LIMIT: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredObject {
+    pub root: [u8; 32],
+    pub chunking: Chunking,
+    pub hasher: TreeHasher,
+    pub leaves: Vec<[u8; 32]>,
+    /// Seconds since the Unix epoch at which the current version was stored.
+    pub created_at: u64,
+    /// Earlier versions, oldest first.
+    pub history: Vec<Version>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub root: [u8; 32],
+    pub leaves: Vec<[u8; 32]>,
+    pub created_at: u64,
+}
+
+impl StoredObject {
+    /// The object as it was when its root was `root`, if that version is
+    /// the current one or still retained.
+    pub fn at(self, root: &[u8; 32]) -> Option<StoredObject> {
+        if self.root == *root {
+            return Some(self);
+        }
+        let version = self.history.iter().rev().find(|version| version.root == *root)?.clone();
+        Some(StoredObject {
+            root: version.root,
+            leaves: version.leaves,
+            created_at: version.created_at,
+            ..self
+        })
+    }
+
+    /// Makes the version with root `root`, the current one or a retained
+    /// one, current again; [`Store::update`] moves the version it replaces
+    /// into the history. `false` if there is no such version.
+    pub fn roll_back(&mut self, root: &[u8; 32]) -> bool {
+        match self.clone().at(root) {
+            Some(version) => {
+                self.root = version.root;
+                self.leaves = version.leaves;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Every retained version, oldest first, ending with the current one.
+    pub fn versions(&self) -> impl Iterator<Item = (&[u8; 32], &[[u8; 32]], u64)> {
+        self.history
+            .iter()
+            .map(|version| (&version.root, version.leaves.as_slice(), version.created_at))
+            .chain([(&self.root, self.leaves.as_slice(), self.created_at)])
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ObjectRecord {
+    root: String,
+    chunking: Chunking,
+    #[serde(flatten)]
+    hasher: TreeHasher,
+    leaves: Vec<String>,
+    #[serde(default)]
+    created_at: u64,
+    #[serde(default)]
+    history: Vec<VersionRecord>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VersionRecord {
+    root: String,
+    leaves: Vec<String>,
+    created_at: u64,
+}
+
+#[derive(Debug)]
+pub struct Store {
+    dir: PathBuf,
+    history_limit: usize,
+    updates: Mutex<()>,
+    /// Files written since the last flush.
+    unsynced: Mutex<Vec<PathBuf>>,
+    usage: Mutex<Usage>,
+}
+
+/// What the store holds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub objects: usize,
+    /// Bytes of chunks, each stored once however many objects share it.
+    pub bytes: u64,
+}
+
+impl Store {
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Store> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(dir.join("objects"))?;
+        fs::create_dir_all(dir.join("chunks"))?;
+        let usage = Usage {
+            objects: count_files(&dir.join("objects"))?.0,
+            bytes: count_files(&dir.join("chunks"))?.1,
+        };
+        Ok(Store {
+            dir,
+            history_limit: DEFAULT_HISTORY_LIMIT,
+            updates: Mutex::new(()),
+            unsynced: Mutex::new(Vec::new()),
+            usage: Mutex::new(usage),
+        })
+    }
+
+    pub fn usage(&self) -> Usage {
+        *self.usage.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Persists the chunks of an object under a freshly assigned id.
+    pub fn put(
+        &self,
+        root: [u8; 32],
+        chunking: Chunking,
+        hasher: TreeHasher,
+        chunks: &[&[u8]],
+    ) -> io::Result<ObjectId> {
+        let leaves = chunks
+            .iter()
+            .map(|chunk| self.put_chunk(hasher, chunk))
+            .collect::<io::Result<_>>()?;
+
+        let object_id = ObjectId::random();
+        let object = StoredObject { root, chunking, hasher, leaves, created_at: now(), history: Vec::new() };
+        self.write_record(&object_id, &object)?;
+        self.usage.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).objects += 1;
+        Ok(object_id)
+    }
+
+    /// Stores a chunk unless it is already there, returning its leaf.
+    pub fn put_chunk(&self, hasher: TreeHasher, chunk: &[u8]) -> io::Result<[u8; 32]> {
+        let leaf = hash_leaf(hasher, chunk);
+        let path = self.chunk_path(hasher, &leaf);
+        if path.exists() {
+            return Ok(leaf);
+        }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = temporary_path(&path);
+        fs::write(&tmp, chunk)?;
+        let linked = fs::hard_link(&tmp, &path);
+        let _ = fs::remove_file(&tmp);
+        match linked {
+            Ok(()) => {
+                self.unsynced.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(path);
+                self.usage.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).bytes += chunk.len() as u64;
+            }
+            // another upload stored the same chunk in the meantime, and counted it
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+        Ok(leaf)
+    }
+
+    /// Changes an object in place: `change` gets the current version and
+    /// edits it, and what it leaves behind replaces the object's record
+    /// unless it fails. A version with a new root pushes the one before into
+    /// the history. `None` if there is no such object.
+    pub fn update<T, F>(&self, object_id: &ObjectId, change: F) -> Result<Option<T>>
+    where
+        F: FnOnce(&mut StoredObject) -> Result<T>,
+    {
+        let _guard = self.updates.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut object = match self.get(object_id)? {
+            Some(object) => object,
+            None => return Ok(None),
+        };
+        let previous = Version {
+            root: object.root,
+            leaves: object.leaves.clone(),
+            created_at: object.created_at,
+        };
+        let result = change(&mut object)?;
+        if object.root != previous.root {
+            object.history.push(previous);
+            let excess = object.history.len().saturating_sub(self.history_limit);
+            object.history.drain(..excess);
+            object.created_at = now();
+        }
+        self.write_record(object_id, &object)?;
+        Ok(Some(result))
+    }
+
+    pub fn get(&self, object_id: &ObjectId) -> io::Result<Option<StoredObject>> {
+        let json = match fs::read(self.object_path(object_id)) {
+            Ok(json) => json,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let record: ObjectRecord = serde_json::from_slice(&json)?;
+        let history = record
+            .history
+            .iter()
+            .map(|version| {
+                Ok(Version {
+                    root: decode_hash(&version.root)?,
+                    leaves: decode_hashes(&version.leaves)?,
+                    created_at: version.created_at,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Some(StoredObject {
+            root: decode_hash(&record.root)?,
+            chunking: record.chunking,
+            hasher: record.hasher,
+            leaves: decode_hashes(&record.leaves)?,
+            created_at: record.created_at,
+            history,
+        }))
+    }
+
+    pub fn read_chunk(&self, hasher: TreeHasher, leaf: &[u8; 32]) -> io::Result<Vec<u8>> {
+        fs::read(self.chunk_path(hasher, leaf))
+    }
+
+    fn write_record(&self, object_id: &ObjectId, object: &StoredObject) -> io::Result<()> {
+        let record = ObjectRecord {
+            root: hex::encode(object.root),
+            chunking: object.chunking,
+            hasher: object.hasher,
+            leaves: object.leaves.iter().map(hex::encode).collect(),
+            created_at: object.created_at,
+            history: object
+                .history
+                .iter()
+                .map(|version| VersionRecord {
+                    root: hex::encode(version.root),
+                    leaves: version.leaves.iter().map(hex::encode).collect(),
+                    created_at: version.created_at,
+                })
+                .collect(),
+        };
+        let json = serde_json::to_vec_pretty(&record)?;
+        self.write_atomically(&self.object_path(object_id), &json)
+    }
+
+    fn write_atomically(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let tmp = temporary_path(path);
+        fs::write(&tmp, contents)?;
+        fs::rename(&tmp, path)?;
+        self.unsynced.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(path.to_path_buf());
+        Ok(())
+    }
+
+    /// Syncs every file written since the last flush, and the directories
+    /// they were renamed into, to disk. Waits for an update in progress.
+    pub fn flush(&self) -> io::Result<()> {
+        let _guard = self.updates.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut paths = std::mem::take(&mut *self.unsynced.lock().unwrap_or_else(|poisoned| poisoned.into_inner()));
+        paths.sort();
+        paths.dedup();
+        for path in &paths {
+            fs::File::open(path)?.sync_all()?;
+        }
+        // a rename only lasts once the directory holding it is synced too
+        #[cfg(unix)]
+        {
+            let mut dirs: Vec<&Path> = paths.iter().filter_map(|path| path.parent()).collect();
+            dirs.sort();
+            dirs.dedup();
+            for dir in dirs {
+                fs::File::open(dir)?.sync_all()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn object_path(&self, object_id: &ObjectId) -> PathBuf {
+        self.dir.join("objects").join(format!("{}.json", object_id))
+    }
+
+    fn chunk_path(&self, hasher: TreeHasher, leaf: &[u8; 32]) -> PathBuf {
+        let mut path = self.dir.join("chunks");
+        if hasher != TreeHasher::default() {
+            path.push(hasher.to_string());
+        }
+        path.join(hex::encode(leaf))
+    }
+}
+
+fn decode_hash(value: &str) -> io::Result<[u8; 32]> {
+    let mut hash = [0u8; 32];
+    hex::decode_to_slice(value, &mut hash)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(hash)
+}
+
+fn decode_hashes(values: &[String]) -> io::Result<Vec<[u8; 32]>> {
+    values.iter().map(|value| decode_hash(value)).collect()
+}
+
+/// A name next to `path` to write its content under before it goes in place.
+fn temporary_path(path: &Path) -> PathBuf {
+    path.with_extension(format!("tmp{:08x}", rand::random::<u32>()))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Number and total size of the files under `dir`, leaving out temporary
+/// ones a crash may have left behind.
+fn count_files(dir: &Path) -> io::Result<(usize, u64)> {
+    let mut count = 0;
+    let mut bytes = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            let (files, size) = count_files(&entry.path())?;
+            count += files;
+            bytes += size;
+        } else if !entry.path().extension().is_some_and(|extension| extension.to_string_lossy().starts_with("tmp")) {
+            count += 1;
+            bytes += metadata.len();
+        }
+    }
+    Ok((count, bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use merkle_storage_core::{Error, HashAlgorithm};
+
+    use super::*;
+
+    /// A directory of its own for one test, removed when it ends.
+    struct ScratchDir(PathBuf);
+
+    impl ScratchDir {
+        fn new() -> ScratchDir {
+            ScratchDir(env::temp_dir().join(format!("merkle-storage-store-{:08x}", rand::random::<u32>())))
+        }
+    }
+
+    impl Drop for ScratchDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn objects_survive_reopening_the_store() {
+        let dir = ScratchDir::new();
+        let hasher = TreeHasher::new(HashAlgorithm::Blake3);
+        let chunks: [&[u8]; 3] = [b"first chunk", b"second chunk", b"last"];
+        let store = Store::open(&dir.0).unwrap();
+        let object_id = store.put([9; 32], Chunking::Fixed { size: 12 }, hasher, &chunks).unwrap();
+        let stored = store.get(&object_id).unwrap().unwrap();
+        store.flush().unwrap();
+        drop(store);
+
+        let store = Store::open(&dir.0).unwrap();
+        let object = store.get(&object_id).unwrap().unwrap();
+        assert_eq!(object, stored);
+        assert_eq!(object.root, [9; 32]);
+        assert_eq!(object.hasher, hasher);
+        assert_eq!(object.leaves.len(), chunks.len());
+        for (leaf, chunk) in object.leaves.iter().zip(chunks) {
+            assert_eq!(*leaf, hash_leaf(hasher, chunk));
+            assert_eq!(store.read_chunk(hasher, leaf).unwrap(), chunk);
+        }
+        assert_eq!(store.get(&ObjectId([0; 16])).unwrap(), None);
+    }
+
+    #[test]
+    fn usage_is_counted_again_on_reopening() {
+        let dir = ScratchDir::new();
+        let store = Store::open(&dir.0).unwrap();
+        assert_eq!(store.usage(), Usage::default());
+        let chunking = Chunking::Fixed { size: 4 };
+        store.put([1; 32], chunking, TreeHasher::default(), &[b"abcd", b"efgh"]).unwrap();
+        // a chunk shared with the first object is stored and counted once
+        store.put([2; 32], chunking, TreeHasher::default(), &[b"abcd", b"ij"]).unwrap();
+        // the same content under another hasher is a different chunk
+        store.put([3; 32], chunking, TreeHasher::new(HashAlgorithm::Blake3), &[b"abcd"]).unwrap();
+        let usage = store.usage();
+        assert_eq!(usage, Usage { objects: 3, bytes: 14 });
+        drop(store);
+
+        // leftovers of writes a crash interrupted are not counted
+        fs::write(dir.0.join("chunks").join("0123.tmp0000abcd"), b"half a chunk").unwrap();
+        fs::write(dir.0.join("objects").join("0123.tmp0000abcd"), b"{").unwrap();
+        let store = Store::open(&dir.0).unwrap();
+        assert_eq!(store.usage(), usage);
+    }
+    #[test]
+    fn a_chunk_stored_at_once_by_several_uploads_counts_once() {
+        let dir = ScratchDir::new();
+        let store = Store::open(&dir.0).unwrap();
+        let chunk = vec![5; 1000];
+        let leaves: Vec<[u8; 32]> = std::thread::scope(|scope| {
+            let uploads: Vec<_> =
+                (0..16).map(|_| scope.spawn(|| store.put_chunk(TreeHasher::default(), &chunk).unwrap())).collect();
+            uploads.into_iter().map(|upload| upload.join().unwrap()).collect()
+        });
+        assert!(leaves.iter().all(|leaf| *leaf == leaves[0]));
+        assert_eq!(store.usage().bytes, 1000);
+        assert_eq!(store.read_chunk(TreeHasher::default(), &leaves[0]).unwrap(), chunk);
+        // no temporary file is left behind
+        assert_eq!(fs::read_dir(dir.0.join("chunks")).unwrap().count(), 1);
+        drop(store);
+        assert_eq!(Store::open(&dir.0).unwrap().usage().bytes, 1000);
+    }
+
+    /// Replaces the leaves of an object with a new version of root `[root; 32]`.
+    fn change(store: &Store, object_id: &ObjectId, root: u8) -> Option<()> {
+        store
+            .update(object_id, |object| {
+                object.root = [root; 32];
+                object.leaves = vec![[root; 32]; root as usize];
+                Ok(())
+            })
+            .unwrap()
+    }
+
+    fn history_roots(store: &Store, object_id: &ObjectId) -> Vec<u8> {
+        store.get(object_id).unwrap().unwrap().history.iter().map(|version| version.root[0]).collect()
+    }
+
+    #[test]
+    fn updates_push_the_previous_version_into_the_history() {
+        let dir = ScratchDir::new();
+        let store = Store::open(&dir.0).unwrap();
+        let object_id = store.put([1; 32], Chunking::default(), TreeHasher::default(), &[b"chunk"]).unwrap();
+        let first = store.get(&object_id).unwrap().unwrap();
+
+        change(&store, &object_id, 2).unwrap();
+        let object = store.get(&object_id).unwrap().unwrap();
+        assert_eq!(object.root, [2; 32]);
+        assert_eq!(object.leaves, vec![[2; 32]; 2]);
+        assert_eq!(object.history, [Version { root: first.root, leaves: first.leaves, created_at: first.created_at }]);
+
+        // an update keeping the root, or failing, leaves the history alone
+        store.update(&object_id, |_| Ok(())).unwrap().unwrap();
+        let failed = store.update(&object_id, |object| {
+            object.root = [3; 32];
+            Err::<(), _>(Error::InvalidInput("no".to_string()))
+        });
+        assert!(failed.is_err());
+        assert_eq!(store.get(&object_id).unwrap().unwrap(), object);
+
+        assert_eq!(change(&store, &ObjectId([0; 16]), 4), None);
+    }
+
+    #[test]
+    fn the_history_keeps_the_latest_versions() {
+        let dir = ScratchDir::new();
+        let mut store = Store::open(&dir.0).unwrap();
+        store.history_limit = 3;
+        let object_id = store.put([1; 32], Chunking::default(), TreeHasher::default(), &[b"chunk"]).unwrap();
+        for root in 2..=4 {
+            change(&store, &object_id, root).unwrap();
+        }
+        assert_eq!(history_roots(&store, &object_id), [1, 2, 3]);
+        for root in 5..=6 {
+            change(&store, &object_id, root).unwrap();
+        }
+        assert_eq!(history_roots(&store, &object_id), [3, 4, 5]);
+        let object = store.get(&object_id).unwrap().unwrap();
+        let versions: Vec<u8> = object.versions().map(|(root, _, _)| root[0]).collect();
+        assert_eq!(versions, [3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn current_and_retained_versions_can_be_looked_up() {
+        let dir = ScratchDir::new();
+        let store = Store::open(&dir.0).unwrap();
+        let object_id = store.put([1; 32], Chunking::default(), TreeHasher::default(), &[b"chunk"]).unwrap();
+        change(&store, &object_id, 2).unwrap();
+        change(&store, &object_id, 3).unwrap();
+        let object = store.get(&object_id).unwrap().unwrap();
+
+        assert_eq!(object.clone().at(&[3; 32]), Some(object.clone()));
+        let retained = object.clone().at(&[2; 32]).unwrap();
+        assert_eq!(retained.root, [2; 32]);
+        assert_eq!(retained.leaves, vec![[2; 32]; 2]);
+        assert_eq!(retained.created_at, object.history[1].created_at);
+        assert_eq!((retained.chunking, retained.hasher), (object.chunking, object.hasher));
+        assert_eq!(object.clone().at(&[1; 32]).unwrap().leaves, [hash_leaf(object.hasher, b"chunk")]);
+        assert_eq!(object.at(&[4; 32]), None);
+    }
+
+    #[test]
+    fn a_rollback_moves_the_replaced_version_into_the_history() {
+        let dir = ScratchDir::new();
+        let store = Store::open(&dir.0).unwrap();
+        let object_id = store.put([1; 32], Chunking::default(), TreeHasher::default(), &[b"chunk"]).unwrap();
+        let first = store.get(&object_id).unwrap().unwrap();
+        change(&store, &object_id, 2).unwrap();
+
+        assert_eq!(store.update(&object_id, |object| Ok(object.roll_back(&[3; 32]))).unwrap(), Some(false));
+        assert_eq!(store.update(&object_id, |object| Ok(object.roll_back(&first.root))).unwrap(), Some(true));
+        let object = store.get(&object_id).unwrap().unwrap();
+        assert_eq!((object.root, &object.leaves), (first.root, &first.leaves));
+        assert_eq!(history_roots(&store, &object_id), [1, 2]);
+        assert_eq!(object.history[1].leaves, vec![[2; 32]; 2]);
+        // and the replaced version can still be proven and restored
+        assert!(object.at(&[2; 32]).is_some());
+    }
+}